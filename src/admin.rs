@@ -0,0 +1,109 @@
+// This module contains the endpoints for operators. They require
+// `Authorization: Bearer <admin_token>` and are disabled if no token is configured.
+
+use axum::extract::{Path, State};
+use axum::http::{header::AUTHORIZATION, HeaderMap, StatusCode};
+use axum::Json;
+use serde::Serialize;
+use std::sync::PoisonError;
+
+use crate::animals::Animal;
+use crate::config::ServerConfig;
+use crate::{refresh_shards, AppState, Shard};
+
+fn authorize(state: &AppState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let Some(token) = &state.cfg.admin_token else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+    let provided = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match provided {
+        Some(provided) if constant_time_eq(provided.as_bytes(), token.as_bytes()) => Ok(()),
+        _ => {
+            tracing::warn!("Unauthorized admin request");
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
+// Doesn't let the comparison time reveal the length of the matching prefix.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Serialize)]
+pub struct RefreshSummary {
+    animal: String,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// Refreshes all the shards immediately, e.g. after an outage of a fact provider.
+pub(crate) async fn refresh(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<RefreshSummary>>, StatusCode> {
+    authorize(&state, &headers)?;
+    tracing::info!("Manual shard refresh requested");
+    let report = refresh_shards(&state, 1).await;
+    report.log_errors();
+    let summary = report
+        .0
+        .into_iter()
+        .map(|(animal, result)| RefreshSummary {
+            animal: animal.to_string(),
+            success: result.is_ok(),
+            error: result.err().map(|e| format!("{:?}", e)),
+        })
+        .collect();
+    Ok(Json(summary))
+}
+
+#[derive(Serialize)]
+pub struct EvictionSummary {
+    animal: String,
+    shards: usize,
+    // Summed across the shards
+    evicted_facts: usize,
+}
+
+// Empties the shards of an animal, e.g. if its provider has returned bad facts, so that
+// they aren't served until the next refresh; `/health` fails meanwhile.
+pub(crate) async fn evict(
+    State(state): State<AppState>,
+    Path(animal): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<EvictionSummary>, StatusCode> {
+    authorize(&state, &headers)?;
+    let shard_set = animal
+        .parse::<Animal>()
+        .ok()
+        .and_then(|animal| state.cache.iter().find(|s| s.animal == animal))
+        .ok_or(StatusCode::NOT_FOUND)?;
+    tracing::warn!("Eviction of the {} facts requested", shard_set.animal);
+    let mut evicted_facts = 0;
+    for shard in &shard_set.shards {
+        // A poisoned shard is replaced as well
+        let mut shard = shard.lock().unwrap_or_else(PoisonError::into_inner);
+        evicted_facts += shard.facts.len();
+        *shard = Shard::new(vec![]);
+    }
+    Ok(Json(EvictionSummary {
+        animal: shard_set.animal.to_string(),
+        shards: shard_set.shards.len(),
+        evicted_facts,
+    }))
+}
+
+// The effective config (i.e. after defaults are applied and animals are deduplicated),
+// secrets are redacted.
+pub(crate) async fn config(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<ServerConfig>, StatusCode> {
+    authorize(&state, &headers)?;
+    Ok(Json(state.cfg))
+}
@@ -1,65 +1,113 @@
 // This module contains the code requesting facts about different animals,
 // validating the responses, etc.
 
-#[cfg(not(test))]
-use axum::http::StatusCode;
+use axum::http::{header::RETRY_AFTER, StatusCode};
+use chrono::{DateTime, Utc};
 use clap::ValueEnum;
 use serde::Deserialize;
 #[cfg(test)]
 use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
 
 use crate::errors::AppError;
+use crate::normalization::Normalization;
+use crate::providers::Provider;
 use crate::Shard;
 
-#[derive(Clone, Copy, ValueEnum, Debug)]
+#[cfg(not(any(feature = "dog", feature = "cat")))]
+compile_error!("At least one animal feature (`dog`, `cat`) must be enabled");
+
+// Each animal's provider can be excluded from the build with a cargo feature.
+#[derive(Clone, Copy, ValueEnum, Debug, PartialEq, Eq, Hash)]
 pub enum Animal {
+    #[cfg(feature = "dog")]
     Dog,
+    #[cfg(feature = "cat")]
     Cat,
     // New animal can be added here
 }
 
-impl ToString for Animal {
-    fn to_string(&self) -> String {
+impl fmt::Display for Animal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Self::Dog => "dog".to_string(),
-            Self::Cat => "cat".to_string(),
+            #[cfg(feature = "dog")]
+            Self::Dog => write!(f, "dog"),
+            #[cfg(feature = "cat")]
+            Self::Cat => write!(f, "cat"),
         }
     }
 }
 
-pub fn url(animal: &Animal, shard_size: usize) -> String {
-    match animal {
-        Animal::Dog => format!(
-            "https://dog-api.kinduff.com/api/facts?number={}",
-            shard_size
-        ),
-        Animal::Cat => format!(
-            "https://cat-fact.herokuapp.com/facts/random?type=cat&amount={}",
-            shard_size
-        ),
+// Including the ones whose features are disabled
+const ALL_ANIMALS: [&str; 2] = ["dog", "cat"];
+
+// Round-trips with `to_string`; case-insensitive.
+impl FromStr for Animal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            #[cfg(feature = "dog")]
+            "dog" => Ok(Self::Dog),
+            #[cfg(feature = "cat")]
+            "cat" => Ok(Self::Cat),
+            name if ALL_ANIMALS.contains(&name) => Err(format!(
+                "animal `{name}` isn't supported by this build, enable the `{name}` cargo feature"
+            )),
+            _ => Err(format!("unknown animal `{s}`")),
+        }
     }
 }
 
 // It could have been a method of the `Animal` trait implemented for both species.
 // As there are not many sepcies-specific parameters, I decided not to create a separate struct for each.
-pub fn validate_batch(body: String, animal: &Animal, shard_size: usize) -> Result<Shard, AppError> {
+pub fn validate_batch(
+    body: String,
+    animal: &Animal,
+    shard_size: usize,
+    normalization: &Normalization,
+) -> Result<Shard, AppError> {
     let shard = match animal {
+        #[cfg(feature = "dog")]
         Animal::Dog => validate_dog_facts(body, shard_size)?,
+        #[cfg(feature = "cat")]
         Animal::Cat => validate_cat_facts(body, shard_size)?,
     };
-    validate_shard(shard, animal)
+    validate_shard(shard, animal, normalization)
 }
 
 // Animal-agnostic fact validation. Almost empty now, but more checks can be added later.
-pub fn validate_shard(shard: Shard, animal: &Animal) -> Result<Shard, AppError> {
-    if shard.facts.contains(&String::from("")) {
+pub fn validate_shard(
+    shard: Shard,
+    animal: &Animal,
+    normalization: &Normalization,
+) -> Result<Shard, AppError> {
+    // Normalization goes first, so that the checks concern the facts to be stored.
+    let shard = if *normalization == Normalization::default() {
+        shard
+    } else {
+        let mut normalized = Shard::new(
+            shard
+                .facts
+                .into_iter()
+                .map(|f| normalization.apply(f))
+                .collect(),
+        );
+        normalized.weights = shard.weights;
+        normalized
+    };
+    // Whitespace-only facts are as useless as empty ones
+    if shard.facts.iter().any(|f| f.trim().is_empty()) {
         // Such facts could just have been excluded, but it requires some
         // additional logic concerning minimum shard size and its replenishment.
         // Currently this code just helps to notice empty facts in responses
         // (and it hasn't noticed any such fact yet).
-        return Err(AppError::InvalidData(
-            format!("An empty {:?} fact received", animal)
-        ));
+        return Err(AppError::InvalidData(format!(
+            "An empty {:?} fact received",
+            animal
+        )));
     };
     // It might make sense to exclude too long facts from the batches so as
     // to control the amount of memory used, the fact providers can't be really trusted.
@@ -69,37 +117,186 @@ pub fn validate_shard(shard: Shard, animal: &Animal) -> Result<Shard, AppError>
 #[cfg(not(test))]
 pub async fn fetch_raw_facts(
     client: &reqwest::Client,
-    animal: &Animal,
+    provider: &Provider,
+    mirror: usize,
     shard_size: usize,
+    max_response_bytes: usize,
+) -> Result<String, AppError> {
+    fetch_url(
+        client,
+        &provider.mirror_url(mirror, shard_size),
+        max_response_bytes,
+    )
+    .await
+}
+
+pub async fn fetch_url(
+    client: &reqwest::Client,
+    url: &str,
+    max_response_bytes: usize,
 ) -> Result<String, AppError> {
-    let url = url(animal, shard_size);
     let response = client.get(url).send().await?;
     match response.status() {
         StatusCode::OK => (),
+        StatusCode::TOO_MANY_REQUESTS => {
+            return Err(AppError::RateLimited(retry_after(response.headers())))
+        }
         // It doesn't seem necessary to implement retries, as these requests
         // are being re-sent routinely. Just wait for the next run.
         code => return Err(AppError::UnexpectedStatusCode(code)),
     };
-    Ok(response.text().await?)
+    read_body(response, max_response_bytes).await
+}
+
+// A provider can't postpone our requests for longer than this, whatever it sends.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+// `Retry-After` is either a number of seconds or an HTTP date; a date in the past means no delay.
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    let delay = match value.parse::<u64>() {
+        Ok(sec) => Duration::from_secs(sec),
+        Err(_) => {
+            let date = DateTime::parse_from_rfc2822(value).ok()?;
+            (date.with_timezone(&Utc) - Utc::now())
+                .to_std()
+                .unwrap_or_default()
+        }
+    };
+    Some(delay.min(MAX_RETRY_AFTER))
+}
+
+// The body is read chunk by chunk, so that an oversized response is rejected
+// before it's buffered entirely; `Content-Length` (if any) allows to reject it even earlier.
+async fn read_body(
+    mut response: reqwest::Response,
+    max_response_bytes: usize,
+) -> Result<String, AppError> {
+    if let Some(len) = response.content_length() {
+        if len > max_response_bytes as u64 {
+            return Err(AppError::ResponseTooLarge(max_response_bytes));
+        }
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > max_response_bytes {
+            return Err(AppError::ResponseTooLarge(max_response_bytes));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
 }
 
 // The `mockall` library could be used instead.
-// If need be, a custom attribute can be created to allow running tests
-// with both real and fake `fetch_raw_facts` by choice.
+// Only the built-in providers are faked; others (e.g. mock servers) are requested for real.
+// Tests may also request the built-in providers for real, see `fake::disable`.
 #[cfg(test)]
 pub async fn fetch_raw_facts(
-    _: &reqwest::Client,
-    animal: &Animal,
+    client: &reqwest::Client,
+    provider: &Provider,
+    mirror: usize,
     shard_size: usize,
+    max_response_bytes: usize,
 ) -> Result<String, AppError> {
+    if !provider.is_builtin() || !fake::enabled() {
+        let url = provider.mirror_url(mirror, shard_size);
+        return fetch_url(client, &url, max_response_bytes).await;
+    }
+    let animal = &provider.format;
+    fake::record_call(client, animal);
+    tokio::time::sleep(fake::delay()).await;
+    if let Some(response) = fake::scripted_response(animal) {
+        return response;
+    }
     match animal {
         // All the fake raw facts generated here should be valid, as
         // invalid fake raw facts can be fed directly into validators.
+        #[cfg(feature = "dog")]
         Animal::Dog => Ok(fake_raw_dog_facts(shard_size)),
+        #[cfg(feature = "cat")]
         Animal::Cat => Ok(fake_raw_cat_facts(shard_size)),
     }
 }
 
+// The state of the fake fact provider, which allows tests to check how it's used.
+// `#[tokio::test]` runs each test on a separate thread, hence the state is thread-local.
+#[cfg(test)]
+pub mod fake {
+    use std::cell::{Cell, RefCell};
+    use std::collections::{HashMap, VecDeque};
+    use std::time::Duration;
+
+    use super::Animal;
+    use crate::errors::AppError;
+
+    pub struct Call {
+        pub animal: Animal,
+        pub client: *const reqwest::Client,
+    }
+
+    thread_local! {
+        static CALLS: RefCell<Vec<Call>> = const { RefCell::new(vec![]) };
+        static DELAY: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+        static ENABLED: Cell<bool> = const { Cell::new(true) };
+        static SCRIPTS: RefCell<HashMap<Animal, VecDeque<Result<String, AppError>>>> =
+            RefCell::new(HashMap::new());
+    }
+
+    // Makes the current test use the real fact providers, see the `integration` feature
+    #[cfg_attr(not(feature = "integration"), allow(dead_code))]
+    pub fn disable() {
+        ENABLED.with(|e| e.set(false));
+    }
+
+    pub(super) fn enabled() -> bool {
+        ENABLED.with(|e| e.get())
+    }
+
+    // Imitates the response time of fact providers
+    pub fn set_delay(delay: Duration) {
+        DELAY.with(|d| d.set(delay));
+    }
+
+    pub(super) fn delay() -> Duration {
+        DELAY.with(|d| d.get())
+    }
+
+    // The responses are returned one by one instead of valid fake facts,
+    // e.g. to imitate provider failures.
+    pub fn script(animal: Animal, responses: Vec<Result<String, AppError>>) {
+        SCRIPTS.with(|scripts| {
+            scripts
+                .borrow_mut()
+                .entry(animal)
+                .or_default()
+                .extend(responses)
+        });
+    }
+
+    pub(super) fn scripted_response(animal: &Animal) -> Option<Result<String, AppError>> {
+        SCRIPTS.with(|scripts| {
+            scripts
+                .borrow_mut()
+                .get_mut(animal)
+                .and_then(|responses| responses.pop_front())
+        })
+    }
+
+    pub(super) fn record_call(client: &reqwest::Client, animal: &Animal) {
+        CALLS.with(|calls| {
+            calls.borrow_mut().push(Call {
+                animal: *animal,
+                client,
+            })
+        });
+    }
+
+    pub fn calls() -> Vec<Call> {
+        CALLS.with(|calls| calls.take())
+    }
+}
+
+#[cfg(feature = "dog")]
 #[derive(Deserialize, Debug)]
 #[cfg_attr(test, derive(Serialize))]
 struct DogFactBatch {
@@ -107,6 +304,7 @@ struct DogFactBatch {
     success: bool,
 }
 
+#[cfg(feature = "dog")]
 fn validate_dog_facts(body: String, shard_size: usize) -> Result<Shard, AppError> {
     match serde_json::from_str::<DogFactBatch>(&body) {
         Ok(batch) => {
@@ -128,10 +326,12 @@ fn validate_dog_facts(body: String, shard_size: usize) -> Result<Shard, AppError
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "dog"))]
 fn fake_raw_dog_facts(shard_size: usize) -> String {
     let batch = DogFactBatch {
-        facts: vec!["a dog fact".to_string(); shard_size],
+        facts: (0..shard_size)
+            .map(|i| format!("dog fact #{}", i))
+            .collect(),
         success: true,
     };
     serde_json::to_string(&batch).unwrap()
@@ -139,12 +339,41 @@ fn fake_raw_dog_facts(shard_size: usize) -> String {
 
 // Irrelevent fields are omitted, checking them doesn't seem useful.
 // They can be added later for the sake of fact filtering.
+#[cfg(feature = "cat")]
 #[derive(Deserialize, Debug)]
-#[cfg_attr(test, derive(Serialize, Clone))]
+#[cfg_attr(test, derive(Serialize))]
 struct CatFact {
     text: String,
+    #[serde(default)]
+    status: Option<CatFactStatus>,
+}
+
+#[cfg(feature = "cat")]
+#[derive(Deserialize, Debug)]
+#[cfg_attr(test, derive(Serialize))]
+struct CatFactStatus {
+    // `null` for the facts which haven't been reviewed
+    #[serde(default)]
+    verified: Option<bool>,
 }
 
+// Verified facts are returned more often than the unreviewed ones.
+#[cfg(feature = "cat")]
+const VERIFIED_FACT_WEIGHT: u32 = 3;
+
+#[cfg(feature = "cat")]
+impl CatFact {
+    fn weight(&self) -> u32 {
+        match self.status {
+            Some(CatFactStatus {
+                verified: Some(true),
+            }) => VERIFIED_FACT_WEIGHT,
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(feature = "cat")]
 fn validate_cat_facts(body: String, shard_size: usize) -> Result<Shard, AppError> {
     match serde_json::from_str::<Vec<CatFact>>(&body) {
         Ok(batch) => {
@@ -156,17 +385,241 @@ fn validate_cat_facts(body: String, shard_size: usize) -> Result<Shard, AppError
                 )));
             }
             // One may exclude some facts (e.g. from untrustworthy authors) here.
-            Ok(Shard::new(batch.into_iter().map(|f| f.text).collect()))
+            let weights = batch.iter().map(CatFact::weight).collect();
+            Ok(Shard::new(batch.into_iter().map(|f| f.text).collect()).with_weights(weights))
         }
         Err(e) => Err(AppError::JsonParsingError(e)),
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "cat"))]
 fn fake_raw_cat_facts(shard_size: usize) -> String {
-    let fact = CatFact {
-        text: "a cat fact".into(),
-    };
-    let batch = vec![fact; shard_size];
+    let batch: Vec<_> = (0..shard_size)
+        .map(|i| CatFact {
+            text: format!("cat fact #{}", i),
+            status: None,
+        })
+        .collect();
     serde_json::to_string(&batch).unwrap()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_animal_round_trip() {
+        for animal in Animal::value_variants() {
+            assert_eq!(animal.to_string().parse(), Ok(*animal));
+            assert_eq!(animal.to_string().to_uppercase().parse(), Ok(*animal));
+        }
+        for name in ALL_ANIMALS {
+            match name.parse::<Animal>() {
+                Ok(animal) => assert_eq!(animal.to_string(), name),
+                Err(e) => assert!(e.contains("cargo feature"), "{}", e),
+            }
+        }
+        assert!("cow".parse::<Animal>().is_err());
+        assert!("".parse::<Animal>().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "cat")]
+    fn test_normalized_validation() {
+        let normalization = Normalization {
+            strip_html: true,
+            normalize_whitespace: true,
+            trim: true,
+            capitalize: false,
+        };
+        let body = r#"[{"text": " <b>Cats</b>\n purr &amp; sleep "}, {"text": "ok"}]"#;
+        let shard = validate_batch(body.to_string(), &Animal::Cat, 2, &normalization).unwrap();
+        assert_eq!(shard.facts, ["Cats purr & sleep", "ok"]);
+
+        // The fact is empty once the tags are stripped
+        let body = r#"[{"text": "<p><br/></p>"}, {"text": "ok"}]"#;
+        assert!(
+            validate_batch(body.to_string(), &Animal::Cat, 2, &Normalization::default()).is_ok()
+        );
+        assert!(matches!(
+            validate_batch(body.to_string(), &Animal::Cat, 2, &normalization),
+            Err(AppError::InvalidData(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "cat")]
+    fn test_cat_fact_weights() {
+        let body = r#"[
+            {"text": "a", "status": {"verified": true, "sentCount": 1}},
+            {"text": "b", "status": {"verified": null}},
+            {"text": "c"}
+        ]"#;
+        let shard =
+            validate_batch(body.to_string(), &Animal::Cat, 3, &Normalization::default()).unwrap();
+        assert_eq!(shard.weights, Some(vec![VERIFIED_FACT_WEIGHT, 1, 1]));
+
+        // The weights survive normalization
+        let normalization = Normalization {
+            trim: true,
+            ..Normalization::default()
+        };
+        let shard = validate_batch(body.to_string(), &Animal::Cat, 3, &normalization).unwrap();
+        assert_eq!(shard.weights, Some(vec![VERIFIED_FACT_WEIGHT, 1, 1]));
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let headers = |value: &str| {
+            let mut headers = reqwest::header::HeaderMap::new();
+            headers.insert(RETRY_AFTER, value.parse().unwrap());
+            headers
+        };
+        assert_eq!(retry_after(&headers("120")), Some(Duration::from_secs(120)));
+        let date = (Utc::now() + chrono::Duration::seconds(60)).to_rfc2822();
+        let delay = retry_after(&headers(&date)).unwrap();
+        assert!(delay > Duration::from_secs(58) && delay <= Duration::from_secs(60));
+        assert_eq!(
+            retry_after(&headers("Wed, 21 Oct 2015 07:28:00 GMT")),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            retry_after(&headers("18446744073709551615")),
+            Some(MAX_RETRY_AFTER)
+        );
+        assert_eq!(
+            retry_after(&headers("Fri, 31 Dec 9999 23:59:59 GMT")),
+            Some(MAX_RETRY_AFTER)
+        );
+        assert_eq!(retry_after(&headers("soon")), None);
+        assert_eq!(retry_after(&reqwest::header::HeaderMap::new()), None);
+    }
+
+    mod fuzz {
+        use super::*;
+        use proptest::prelude::*;
+        use serde_json::{json, Value};
+
+        fn arb_json() -> impl Strategy<Value = Value> {
+            let leaf = prop_oneof![
+                Just(Value::Null),
+                any::<bool>().prop_map(Value::from),
+                any::<i64>().prop_map(Value::from),
+                any::<f64>().prop_map(Value::from),
+                ".{0,20}".prop_map(Value::from),
+            ];
+            leaf.prop_recursive(4, 64, 8, |inner| {
+                prop_oneof![
+                    prop::collection::vec(inner.clone(), 0..8).prop_map(Value::from),
+                    prop::collection::hash_map(".{0,8}", inner, 0..8)
+                        .prop_map(|m| Value::Object(m.into_iter().collect())),
+                ]
+            })
+        }
+
+        // Mostly valid facts, sometimes empty or not strings at all
+        fn arb_fact() -> impl Strategy<Value = Value> {
+            prop_oneof![
+                8 => ".{1,40}".prop_map(Value::from),
+                1 => prop_oneof![Just(""), Just(" "), Just("\n\t")].prop_map(Value::from),
+                1 => arb_json(),
+            ]
+        }
+
+        fn validate(body: String, animal: &Animal, shard_size: usize) -> Result<Shard, AppError> {
+            validate_batch(body, animal, shard_size, &Normalization::default())
+        }
+
+        // A shard is either entirely valid or rejected
+        fn check(result: Result<Shard, AppError>, shard_size: usize) -> Result<(), TestCaseError> {
+            if let Ok(shard) = result {
+                prop_assert_eq!(shard.facts.len(), shard_size);
+                prop_assert!(shard.facts.iter().all(|f| !f.trim().is_empty()));
+            }
+            Ok(())
+        }
+
+        // Such facts used to pass validation
+        #[test]
+        #[cfg(feature = "cat")]
+        fn test_whitespace_fact() {
+            let body = json!([{"text": "a fact"}, {"text": " \n"}]).to_string();
+            assert!(matches!(
+                validate(body, &Animal::Cat, 2),
+                Err(AppError::InvalidData(_))
+            ));
+        }
+
+        proptest! {
+            #[test]
+            fn test_arbitrary_bytes(body in prop::collection::vec(any::<u8>(), 0..512), shard_size in 1..10usize) {
+                for animal in Animal::value_variants() {
+                    let body = String::from_utf8_lossy(&body).into_owned();
+                    check(validate(body, animal, shard_size), shard_size)?;
+                }
+            }
+
+            #[test]
+            fn test_arbitrary_json(body in arb_json(), shard_size in 1..10usize) {
+                for animal in Animal::value_variants() {
+                    check(validate(body.to_string(), animal, shard_size), shard_size)?;
+                }
+            }
+
+            #[test]
+            #[cfg(feature = "dog")]
+            fn test_adversarial_dog_facts(
+                facts in prop::collection::vec(arb_fact(), 0..12),
+                success in prop_oneof![4 => Just(json!(true)), 1 => arb_json()],
+                extra in arb_json(),
+                shard_size in 1..10usize,
+            ) {
+                let body = json!({"facts": facts, "success": success, "extra": extra});
+                check(validate(body.to_string(), &Animal::Dog, shard_size), shard_size)?;
+            }
+
+            #[test]
+            #[cfg(feature = "cat")]
+            fn test_adversarial_cat_facts(
+                facts in prop::collection::vec((arb_fact(), arb_json()), 0..12),
+                shard_size in 1..10usize,
+            ) {
+                let body: Vec<_> = facts
+                    .into_iter()
+                    .map(|(text, extra)| json!({"text": text, "extra": extra}))
+                    .collect();
+                check(validate(json!(body).to_string(), &Animal::Cat, shard_size), shard_size)?;
+            }
+
+            #[test]
+            fn test_huge_batches(len in 0..5000usize, shard_size in 1..10usize) {
+                let facts = vec!["fact"; len];
+                for animal in Animal::value_variants() {
+                    for body in [
+                        json!({"facts": facts, "success": true}),
+                        json!(facts.iter().map(|f| json!({"text": f})).collect::<Vec<_>>()),
+                    ] {
+                        check(validate(body.to_string(), animal, shard_size), shard_size)?;
+                    }
+                }
+            }
+        }
+    }
+
+    // Requests the live APIs, so that changes of their formats are noticed.
+    #[cfg(feature = "integration")]
+    #[tokio::test]
+    async fn test_real_providers() {
+        fake::disable();
+        let client = reqwest::Client::new();
+        for animal in Animal::value_variants() {
+            let body = fetch_raw_facts(&client, &Provider::builtin(*animal), 0, 5, 1024 * 1024)
+                .await
+                .unwrap_or_else(|e| panic!("Can't fetch {} facts: {:?}", animal, e));
+            let shard = validate_batch(body, animal, 5, &Normalization::default())
+                .unwrap_or_else(|e| panic!("Invalid {} facts: {:?}", animal, e));
+            assert_eq!(shard.facts.len(), 5);
+            assert!(fake::calls().is_empty());
+        }
+    }
+}
@@ -0,0 +1,102 @@
+// A circuit breaker stops requesting a fact provider which keeps failing,
+// so as not to waste refresh time and not to get throttled. After `failure_threshold`
+// consecutive failures it opens, and the provider isn't requested during the cooldown
+// (the facts fetched before are served meanwhile). Then it half-opens: the next refresh
+// probes the provider, and the breaker closes on success or reopens on failure.
+
+use tokio::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+pub struct CircuitBreaker {
+    // 0 disables the breaker
+    failure_threshold: u32,
+    cooldown: Duration,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            consecutive_failures: 0,
+            opened_at: None,
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        match self.opened_at {
+            None => BreakerState::Closed,
+            Some(opened_at) if opened_at.elapsed() < self.cooldown => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    pub fn allows_requests(&self) -> bool {
+        self.state() != BreakerState::Open
+    }
+
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.opened_at = None;
+    }
+
+    // Returns whether the breaker has (re)opened
+    pub fn record_failure(&mut self) -> bool {
+        self.consecutive_failures += 1;
+        let probe_failed = self.state() == BreakerState::HalfOpen;
+        let threshold_reached =
+            self.failure_threshold > 0 && self.consecutive_failures >= self.failure_threshold;
+        if probe_failed || threshold_reached {
+            self.opened_at = Some(Instant::now());
+        }
+        probe_failed || threshold_reached
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_breaker() {
+        let mut breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        assert!(!breaker.record_failure());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.record_success();
+        assert!(!breaker.record_failure());
+        assert!(breaker.record_failure());
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.allows_requests());
+    }
+
+    #[test]
+    fn test_failed_probe() {
+        let mut breaker = CircuitBreaker::new(2, Duration::ZERO);
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(breaker.allows_requests());
+        // A single failure is enough to reopen a half-open breaker
+        breaker.consecutive_failures = 0;
+        assert!(breaker.record_failure());
+        breaker.record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn test_disabled_breaker() {
+        let mut breaker = CircuitBreaker::new(0, Duration::from_secs(60));
+        for _ in 0..10 {
+            assert!(!breaker.record_failure());
+        }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+}
@@ -1,8 +1,14 @@
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
+use axum::body::HttpBody;
+use axum::extract::State;
+use axum::http::{header, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
 use std::sync::{MutexGuard, PoisonError};
+use std::time::Duration;
 
-use crate::Shard;
+use crate::config::ErrorFormat;
+use crate::{AppState, Shard};
 
 type PoisonedShard<'a> = PoisonError<MutexGuard<'a, Shard>>;
 
@@ -11,9 +17,20 @@ pub enum AppError {
     RequestError(reqwest::Error),
     JsonParsingError(serde_json::Error),
     UnexpectedStatusCode(StatusCode),
+    // Contains the limit that has been exceeded
+    ResponseTooLarge(usize),
+    // A provider has responded with 429; contains its `Retry-After` delay, if any
+    RateLimited(Option<std::time::Duration>),
     InvalidData(String),
     PoisonedShard,
     NoData,
+    InvalidConfig(String),
+    // Fact fetching has been skipped, see `CircuitBreaker`
+    CircuitOpen,
+    // Reading or writing the shared shards has failed, see `store::ShardStore`
+    StoreError(String),
+    // Writing to the standard output has failed, e.g. with `--print-sample` into a closed pipe
+    OutputError(std::io::Error),
 }
 
 impl From<reqwest::Error> for AppError {
@@ -22,6 +39,19 @@ impl From<reqwest::Error> for AppError {
     }
 }
 
+impl From<std::io::Error> for AppError {
+    fn from(value: std::io::Error) -> Self {
+        Self::OutputError(value)
+    }
+}
+
+#[cfg(feature = "redis")]
+impl From<redis::RedisError> for AppError {
+    fn from(value: redis::RedisError) -> Self {
+        Self::StoreError(value.to_string())
+    }
+}
+
 impl<'a> From<PoisonedShard<'a>> for AppError {
     fn from(_: PoisonedShard<'a>) -> Self {
         Self::PoisonedShard
@@ -30,9 +60,90 @@ impl<'a> From<PoisonedShard<'a>> for AppError {
 
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
-        tracing::error!("This code should have never been reached: {:?}", self);
-        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        match self {
+            Self::NoData => no_data_response(None),
+            _ => {
+                tracing::error!("This code should have never been reached: {:?}", self);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
+// Facts may be temporary unavailable, e.g. before the first refresh;
+// `retry_after` is the time they're expected to appear in, if known.
+pub fn no_data_response(retry_after: Option<Duration>) -> Response {
+    let mut response = (
+        StatusCode::SERVICE_UNAVAILABLE,
+        ErrorMessage("No facts are available yet"),
+    )
+        .into_response();
+    if let Some(retry_after) = retry_after {
+        // The header only allows whole seconds
+        let sec = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(sec));
+    }
+    response
+}
+
+// The message of an error response with `ErrorFormat::Json`; it's kept in the extensions
+// of the response, so that the body stays empty with `ErrorFormat::Empty`.
+#[derive(Clone, Copy)]
+pub struct ErrorMessage(pub &'static str);
+
+impl IntoResponse for ErrorMessage {
+    fn into_response(self) -> Response {
+        let mut response = ().into_response();
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+// Wraps error responses into `{"error": {"status": <code>, "message": <text>}}` if configured.
+// The status code and the headers are kept; the message is taken from the body (e.g. of
+// an extractor rejection) or `ErrorMessage`, defaulting to the reason phrase.
+pub async fn error_envelope<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let response = next.run(request).await;
+    let status = response.status();
+    if state.cfg.error_format == ErrorFormat::Empty
+        || !(status.is_client_error() || status.is_server_error())
+    {
+        return response;
+    }
+    let (mut parts, mut body) = response.into_parts();
+    let mut text = vec![];
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => text.extend_from_slice(&chunk),
+            Err(e) => {
+                tracing::error!("Failed to read an error response: {:?}", e);
+                break;
+            }
+        }
     }
+    let text = String::from_utf8_lossy(&text);
+    let message = match parts.extensions.get::<ErrorMessage>() {
+        _ if !text.is_empty() => text.as_ref(),
+        Some(ErrorMessage(message)) => message,
+        None => status.canonical_reason().unwrap_or_default(),
+    };
+    let body = Json(serde_json::json!({
+        "error": {"status": status.as_u16(), "message": message},
+    }))
+    .into_response()
+    .into_body();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    Response::from_parts(parts, body)
 }
 
 pub enum HealthProblem {
@@ -0,0 +1,2917 @@
+use axum::{
+    error_handling::HandleErrorLayer,
+    extract::{Query, State},
+    http::header::CONTENT_TYPE,
+    http::StatusCode,
+    http::{HeaderMap, HeaderName},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    BoxError, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use futures::stream::{self, StreamExt};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::{
+    task,
+    time::{sleep, Duration, Instant},
+};
+use tower::{limit::GlobalConcurrencyLimitLayer, ServiceBuilder};
+use tracing::Instrument;
+
+#[cfg(all(test, feature = "dog", feature = "cat"))]
+use animals::fetch_url;
+use animals::{fetch_raw_facts, validate_batch, Animal};
+use breaker::CircuitBreaker;
+#[cfg(all(test, feature = "dog", feature = "cat"))]
+use config::AnimalSpec;
+use config::ServerConfig;
+use errors::{AppError, HealthProblem};
+use metrics::{DurationStats, LengthHistogram};
+use normalization::Normalization;
+use providers::{MirrorLatencies, Provider};
+use recent::RecentFacts;
+use rng::{RngSource, ThreadRngSource};
+use scheduler::ShardScheduler;
+use store::{ShardStore, StoredShard};
+
+pub mod admin;
+pub mod animals;
+pub mod breaker;
+pub mod config;
+pub mod errors;
+pub mod metrics;
+pub mod normalization;
+pub mod openapi;
+pub mod providers;
+pub mod recent;
+pub mod rng;
+pub mod scheduler;
+pub mod sse;
+pub mod store;
+pub mod translation;
+
+#[derive(Default)]
+pub struct Shard {
+    pub facts: Vec<String>,
+    // Relative frequencies of the facts in the same order; `None` if they're uniform
+    pub weights: Option<Vec<u32>>,
+    pub timestamp: DateTime<Utc>,
+    // Computed once per refresh, so that metrics don't need to iterate over facts.
+    pub length_histogram: LengthHistogram,
+    // Total length of the facts, so that `max_total_fact_bytes` doesn't need to iterate over them
+    pub fact_bytes: usize,
+}
+
+impl Shard {
+    pub fn new(facts: Vec<String>) -> Self {
+        Self {
+            length_histogram: LengthHistogram::from_facts(&facts),
+            fact_bytes: facts.iter().map(String::len).sum(),
+            facts,
+            weights: None,
+            timestamp: Utc::now(),
+        }
+    }
+
+    // Uniform weights aren't stored, so that sampling takes the cheaper path.
+    pub fn with_weights(mut self, weights: Vec<u32>) -> Self {
+        debug_assert_eq!(weights.len(), self.facts.len());
+        self.weights = Some(weights).filter(|w| w.windows(2).any(|pair| pair[0] != pair[1]));
+        self
+    }
+}
+
+// A content-derived fact identifier, so that clients can refer to facts they've seen.
+// It's a 64-bit FNV-1a hash of the fact text (displayed in hex), which is stable
+// across restarts unlike the hashes of `std`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FactId(u64);
+
+impl FactId {
+    pub fn of(fact: &str) -> Self {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in fact.bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        Self(hash)
+    }
+}
+
+impl fmt::Display for FactId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for FactId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        u64::from_str_radix(s, 16).map(Self)
+    }
+}
+
+struct ShardSet {
+    animal: Animal,
+    // Relative frequency of the animal's facts
+    weight: u32,
+    // Tried in order until one of them returns a valid batch
+    providers: Vec<Provider>,
+    // Latencies of the providers' mirrors, in the same order as `providers`
+    latencies: Vec<MirrorLatencies>,
+    // When the providers, in the same order, may be requested again after rate limiting
+    retry_at: Vec<Mutex<Option<Instant>>>,
+    // On the alternatives of the sharded `Mutex` see README.md
+    shards: Vec<Mutex<Shard>>,
+    breaker: Mutex<CircuitBreaker>,
+    // Durations of fetching a shard, including fallbacks to secondary providers
+    fetch_duration: Mutex<DurationStats>,
+}
+
+impl ShardSet {
+    // The breaker state stays consistent even if a thread panics while holding it.
+    fn breaker(&self) -> std::sync::MutexGuard<'_, CircuitBreaker> {
+        self.breaker.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // `Some` while the provider has asked not to be requested, see `AppError::RateLimited`
+    fn rate_limit(&self, provider: usize) -> Option<Duration> {
+        let retry_at = *self.retry_at[provider]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        retry_at
+            .map(|retry_at| retry_at.saturating_duration_since(Instant::now()))
+            .filter(|delay| !delay.is_zero())
+    }
+
+    fn fetch_duration(&self) -> DurationStats {
+        *self
+            .fetch_duration
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// The state is shared by the handlers and the background refresh; cloning it is cheap.
+#[derive(Clone)]
+pub struct AppState {
+    cache: Arc<Vec<ShardSet>>,
+    cfg: ServerConfig,
+    // The client is shared by all refreshes so as to reuse the connections
+    // to fact providers; cloning it is cheap.
+    client: reqwest::Client,
+    // Prevents manual refreshes and the background one from interleaving
+    refresh_lock: Arc<tokio::sync::Mutex<()>>,
+    refresh_duration: Arc<Mutex<DurationStats>>,
+    // Permits for `/fact/stream` connections
+    streams: Arc<tokio::sync::Semaphore>,
+    recent_facts: Arc<RecentFacts>,
+    rng: Arc<dyn RngSource>,
+    // Chooses the shards to read and the order of refreshing them
+    scheduler: Arc<ShardScheduler>,
+    // Starts the grace period of `/health`, see `startup_grace_sec`
+    started: Instant,
+    // The shards shared by replicas, see `redis_url`
+    store: Option<Arc<dyn ShardStore>>,
+}
+
+impl AppState {
+    // E.g. `rng::SeededRngSource` makes fact selection reproducible.
+    pub fn with_rng_source(mut self, source: impl RngSource + 'static) -> Self {
+        self.rng = Arc::new(source);
+        self
+    }
+
+    // Overrides the store set up according to `redis_url`, e.g. to share the shards
+    // of several states in a single process.
+    pub fn with_shard_store(mut self, store: Arc<dyn ShardStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    // A reader loads the shards saved by the writer instead of requesting the providers.
+    fn is_store_reader(&self) -> bool {
+        self.store.is_some() && !self.cfg.redis_writer
+    }
+
+    fn refresh_duration(&self) -> DurationStats {
+        *self
+            .refresh_duration
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Connection reuse settings of the client; unset options keep reqwest's defaults.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct PoolSettings {
+    max_idle_per_host: Option<usize>,
+    // `None` keeps idle connections open indefinitely
+    idle_timeout: Option<Duration>,
+}
+
+impl From<&ServerConfig> for PoolSettings {
+    fn from(cfg: &ServerConfig) -> Self {
+        Self {
+            max_idle_per_host: cfg.pool_max_idle_per_host,
+            idle_timeout: Some(Duration::from_secs(cfg.pool_idle_timeout_sec))
+                .filter(|timeout| !timeout.is_zero()),
+        }
+    }
+}
+
+impl PoolSettings {
+    fn apply(self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        let builder = builder.pool_idle_timeout(self.idle_timeout);
+        match self.max_idle_per_host {
+            Some(max) => builder.pool_max_idle_per_host(max),
+            None => builder,
+        }
+    }
+}
+
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+pub fn init_state(cfg: ServerConfig) -> Result<AppState, AppError> {
+    let mut providers = match &cfg.providers_file {
+        Some(path) => providers::load_providers(path)?,
+        None => HashMap::new(),
+    };
+    let mut cache = Vec::with_capacity(cfg.shard_num);
+    for spec in &cfg.animals {
+        let mut shards = Vec::with_capacity(cfg.shard_num);
+        for _ in 0..cfg.shard_num {
+            shards.push(Mutex::new(Shard::new(vec![])));
+        }
+        let providers = providers
+            .remove(&spec.animal)
+            .unwrap_or_else(|| vec![Provider::builtin(spec.animal)]);
+        cache.push(ShardSet {
+            animal: spec.animal,
+            weight: spec.weight,
+            latencies: providers
+                .iter()
+                .map(|p| MirrorLatencies::new(p.mirror_num()))
+                .collect(),
+            retry_at: providers.iter().map(|_| Mutex::new(None)).collect(),
+            providers,
+            shards,
+            breaker: Mutex::new(CircuitBreaker::new(
+                cfg.breaker_failure_threshold,
+                Duration::from_secs(cfg.breaker_cooldown_sec),
+            )),
+            fetch_duration: Mutex::new(DurationStats::default()),
+        });
+    }
+    let client = PoolSettings::from(&cfg)
+        .apply(reqwest::Client::builder())
+        .timeout(Duration::from_secs(cfg.request_timeout_sec))
+        .user_agent(USER_AGENT)
+        // Sets `Accept-Encoding`; responses are decoded before `max_response_bytes`
+        // is checked, so a compressed body can't bypass the limit.
+        .gzip(true)
+        .brotli(true)
+        .deflate(true)
+        .build()?;
+    Ok(AppState {
+        cache: Arc::new(cache),
+        client,
+        refresh_lock: Arc::new(tokio::sync::Mutex::new(())),
+        refresh_duration: Arc::new(Mutex::new(DurationStats::default())),
+        streams: Arc::new(tokio::sync::Semaphore::new(cfg.max_streams)),
+        recent_facts: Arc::new(RecentFacts::new(cfg.recent_facts_memory)),
+        rng: Arc::new(ThreadRngSource),
+        scheduler: Arc::new(ShardScheduler::new(&cfg)),
+        started: Instant::now(),
+        store: shard_store(&cfg)?,
+        cfg,
+    })
+}
+
+fn shard_store(cfg: &ServerConfig) -> Result<Option<Arc<dyn ShardStore>>, AppError> {
+    let Some(url) = &cfg.redis_url else {
+        return Ok(None);
+    };
+    #[cfg(feature = "redis")]
+    {
+        Ok(Some(Arc::new(store::RedisStore::new(url)?)))
+    }
+    #[cfg(not(feature = "redis"))]
+    {
+        let _ = url;
+        Err(AppError::InvalidConfig(
+            "`redis_url` requires the `redis` cargo feature".to_string(),
+        ))
+    }
+}
+
+// Though fact providers are allowed to become unavailable as server runs,
+// it can't start unless they all have responded correctly.
+// Optionally, one could exclude the species whose fact providers are unavailable,
+// and keep the server running if at least one species' API responded correctly.
+// A reader can't do anything about the store but wait (e.g. for the writer to start),
+// so it starts anyway and serves `503` until it has loaded the shards.
+async fn refresh_on_startup(state: &AppState) -> Result<(), AppError> {
+    let report = refresh_shards(state, state.cfg.startup_concurrency).await;
+    if state.is_store_reader() {
+        report.log_errors();
+        return Ok(());
+    }
+    report.into_result()
+}
+
+// Serves the facts until the server fails; tracing is up to the caller.
+pub async fn run(mut cfg: ServerConfig) -> Result<(), AppError> {
+    cfg.deduplicate_animals()?;
+    cfg.validate()?;
+
+    let state = init_state(cfg)?;
+    if state.cfg.validate_config {
+        tracing::info!("The config is valid");
+        return Ok(());
+    }
+    if let Some(n) = state.cfg.print_sample {
+        return print_sample(&state, n, &mut std::io::stdout()).await;
+    }
+    refresh_on_startup(&state).await?;
+    check_refresh_interval(&state)?;
+    spawn_refresh_task(&state);
+
+    let socket_addr = format!("0.0.0.0:{}", state.cfg.port)
+        .parse()
+        .expect("Unable to parse socket address");
+    axum::Server::bind(&socket_addr)
+        .serve(build_router(state).into_make_service())
+        .await
+        .unwrap();
+
+    Ok(())
+}
+
+// Previews the facts of the configured providers, e.g. when adding a provider;
+// a failure of one animal doesn't prevent printing the others, but is returned.
+async fn print_sample<W: std::io::Write>(
+    state: &AppState,
+    n: usize,
+    out: &mut W,
+) -> Result<(), AppError> {
+    let mut first_error = None;
+    for shard_set in state.cache.as_ref() {
+        match fetch_shard(state, shard_set, 0).await {
+            Ok(shard) => {
+                for fact in shard.facts.iter().take(n) {
+                    writeln!(out, "{}: {}", shard_set.animal, fact)?;
+                }
+            }
+            Err(e) => {
+                tracing::error!("Failed to fetch {} facts: {:?}", shard_set.animal, e);
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+// Without the background refresh the initially fetched facts are served indefinitely
+// (unless refreshed manually); `/health` will report them as stale, though,
+// unless the server runs with `--once`.
+fn spawn_refresh_task(state: &AppState) -> Option<task::JoinHandle<()>> {
+    let Some(interval) = state.cfg.auto_refresh() else {
+        if state.cfg.once {
+            tracing::info!(
+                "Automatic shard refreshing is disabled (`--once`), serving the initial facts"
+            );
+        } else {
+            tracing::info!("Automatic shard refreshing is disabled");
+        }
+        return None;
+    };
+    let state = state.clone();
+    Some(task::spawn(async move {
+        loop {
+            sleep(interval).await;
+            refresh_shards(&state, 1).await.log_errors();
+        }
+    }))
+}
+
+// The routes may be mounted into another app, e.g. with `Router::nest`.
+pub fn build_router(state: AppState) -> Router {
+    let mut limited = Router::new()
+        .route("/fact", get(fact))
+        .route("/fact/stream", get(sse::fact_stream))
+        .route("/facts", get(facts))
+        .route("/stats", get(stats))
+        .route("/metrics", get(metrics::metrics))
+        .route("/openapi.json", get(openapi::openapi))
+        .route("/config", get(admin::config))
+        .route("/admin/refresh", post(admin::refresh))
+        .route("/admin/evict/:animal", post(admin::evict));
+    // Excess requests are rejected at once rather than queued, so that the server
+    // sheds load predictably. The limit is shared by all the routes.
+    if state.cfg.max_concurrent_requests > 0 {
+        limited = limited.layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(|_: BoxError| async {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }))
+                .load_shed()
+                .layer(GlobalConcurrencyLimitLayer::new(
+                    state.cfg.max_concurrent_requests,
+                )),
+        );
+    }
+    // Probes should work under load
+    Router::new()
+        .route("/health", get(health))
+        .merge(limited)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            errors::error_envelope,
+        ))
+        .with_state(state)
+}
+
+// I assume it's OK to return a fact without checking if it's "fresh";
+// this policy allows the server to keep runnig in case a fact provider
+// is temporary unavailable. Naturally, this check could have been performed and
+// a special "no fresh animal facts" error message could have been added.
+async fn fact(
+    State(state): State<AppState>,
+    Query(params): Query<FactParams>,
+) -> Result<Response, Response> {
+    // Unknown ids can't match any fact anyway, so invalid ones are just ignored.
+    let excluded: HashSet<FactId> = match &params.exclude {
+        Some(ids) => ids.split(',').filter_map(|id| id.parse().ok()).collect(),
+        None => HashSet::new(),
+    };
+    let (animal, fact) = select_fact(&state, &excluded).map_err(|e| match e {
+        // E.g. the server is starting; the facts are expected to appear after the next
+        // automatic refresh, if there is one.
+        AppError::NoData => errors::no_data_response(state.cfg.auto_refresh()),
+        e => e.into_response(),
+    })?;
+    let id = params.with_id.then(|| FactId::of(&fact).to_string());
+    let (lang, fact) = match &params.lang {
+        Some(lang) => {
+            let (lang, fact) = translation::translate_or_keep(&state, lang, fact).await;
+            (Some(lang), fact)
+        }
+        None => (None, fact),
+    };
+    let body = serde_json::to_string(&FactResponse {
+        animal: animal.to_string(),
+        fact,
+        id,
+        lang,
+    })
+    .map_err(|e| {
+        tracing::error!("Failed to serialize a fact: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+    // Lets caching proxies and clients detect identical payloads without comparing them;
+    // it's the same hash as the one of fact ids. `Content-Length` is set by hyper.
+    let digest = FactId::of(&body).to_string();
+    Ok((
+        [
+            (CONTENT_TYPE, "application/json".to_string()),
+            (CONTENT_DIGEST, digest),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+const CONTENT_DIGEST: HeaderName = HeaderName::from_static("x-content-digest");
+
+// The number of samples taken to avoid the facts returned recently
+const RESAMPLE_ATTEMPTS: usize = 5;
+
+// If every sample has been returned recently, the last one is returned anyway.
+// Concurrent requests may still get the same fact, as checking the recent facts
+// and remembering the selected one aren't atomic.
+fn select_fact(state: &AppState, excluded: &HashSet<FactId>) -> Result<(Animal, String), AppError> {
+    let mut selected = sample_fact(state, excluded)?;
+    if !state.recent_facts.is_enabled() {
+        return Ok(selected);
+    }
+    for _ in 1..RESAMPLE_ATTEMPTS {
+        if !state.recent_facts.contains(FactId::of(&selected.1)) {
+            break;
+        }
+        selected = sample_fact(state, excluded)?;
+    }
+    state.recent_facts.push(FactId::of(&selected.1));
+    Ok(selected)
+}
+
+// Neither the shard lock nor the `rng` can be held across an `await`, hence the separate function.
+fn sample_fact(state: &AppState, excluded: &HashSet<FactId>) -> Result<(Animal, String), AppError> {
+    let mut rng = state.rng.rng();
+    let shard_set = state
+        .cache
+        .choose_weighted(&mut rng, |s| s.weight)
+        .map_err(|_| AppError::NoData)?;
+    if let Some(fact) = sample_from_set(state, shard_set, excluded, &mut rng)? {
+        return Ok((shard_set.animal, fact));
+    }
+
+    // The chosen animal has no facts (e.g. its refresh has failed), but the others may have.
+    // The number of attempts is bounded by the total number of shards.
+    let mut other_sets: Vec<_> = state
+        .cache
+        .iter()
+        .filter(|s| !std::ptr::eq(*s, shard_set))
+        .collect();
+    other_sets.shuffle(&mut rng);
+    for shard_set in other_sets {
+        for shard in &shard_set.shards {
+            if let Some(fact) = choose_fact(&*shard.lock()?, excluded, &mut rng) {
+                return Ok((shard_set.animal, fact.clone()));
+            }
+        }
+    }
+    Err(AppError::NoData)
+}
+
+// Samples a fact of a single animal; `None` if none of its shards has facts.
+fn sample_from_set<R: Rng>(
+    state: &AppState,
+    shard_set: &ShardSet,
+    excluded: &HashSet<FactId>,
+    rng: &mut R,
+) -> Result<Option<String>, AppError> {
+    let shard = state
+        .scheduler
+        .select(shard_set.animal, &shard_set.shards, rng)?;
+    if let Some(shard) = shard {
+        if let Some(fact) = choose_fact(&*shard.lock()?, excluded, rng) {
+            return Ok(Some(fact.clone()));
+        }
+    }
+    // The chosen shard is empty, but the other shards of the animal may have facts.
+    for shard in &shard_set.shards {
+        if let Some(fact) = choose_fact(&*shard.lock()?, excluded, rng) {
+            return Ok(Some(fact.clone()));
+        }
+    }
+    Ok(None)
+}
+
+// One fact per animal; the animals without facts are omitted rather than failing the response.
+async fn facts(State(state): State<AppState>) -> Result<Json<Vec<FactResponse>>, AppError> {
+    let facts = sample_each_animal(&state)?;
+    Ok(Json(
+        facts
+            .into_iter()
+            .map(|(animal, fact)| FactResponse {
+                animal: animal.to_string(),
+                fact,
+                id: None,
+                lang: None,
+            })
+            .collect(),
+    ))
+}
+
+// The `rng` can't be held across an `await`, hence the separate function.
+fn sample_each_animal(state: &AppState) -> Result<Vec<(Animal, String)>, AppError> {
+    let mut rng = state.rng.rng();
+    let mut facts = Vec::with_capacity(state.cache.len());
+    for shard_set in state.cache.iter() {
+        if let Some(fact) = sample_from_set(state, shard_set, &HashSet::new(), &mut rng)? {
+            facts.push((shard_set.animal, fact));
+        }
+    }
+    Ok(facts)
+}
+
+#[derive(Deserialize)]
+struct FactParams {
+    /// Comma-separated ids of the facts the client has seen recently
+    exclude: Option<String>,
+    /// Whether to include the fact id into the response
+    #[serde(default)]
+    with_id: bool,
+    /// Language to translate the fact into
+    lang: Option<String>,
+}
+
+// Optional fields are omitted unless requested.
+#[derive(Serialize, Debug)]
+#[cfg_attr(test, derive(Deserialize), serde(deny_unknown_fields))]
+struct FactResponse {
+    animal: String,
+    fact: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lang: Option<String>,
+}
+
+// The exclusions are supplied by clients, so the server doesn't need to remember anything.
+// If all the facts of a shard are excluded, any of them is returned.
+fn choose_fact<'a, R: Rng>(
+    shard: &'a Shard,
+    excluded: &HashSet<FactId>,
+    rng: &mut R,
+) -> Option<&'a String> {
+    let facts = &shard.facts;
+    // The weights may be out of sync if the facts have been replaced directly.
+    let weights = shard.weights.as_ref().filter(|w| w.len() == facts.len());
+    if excluded.is_empty() && weights.is_none() {
+        return facts.choose(rng);
+    }
+    let unseen: Vec<_> = (0..facts.len())
+        .filter(|i| !excluded.contains(&FactId::of(&facts[*i])))
+        .collect();
+    let candidates = if unseen.is_empty() {
+        (0..facts.len()).collect()
+    } else {
+        unseen
+    };
+    let i = match weights {
+        Some(weights) => candidates.choose_weighted(rng, |i| weights[*i]).ok(),
+        None => candidates.choose(rng),
+    };
+    i.map(|i| &facts[*i])
+}
+
+#[derive(Deserialize)]
+struct HealthParams {
+    /// Maximal age of a shard (sec) overriding `shard_staleness_sec`
+    max_age: Option<NonZeroU32>,
+}
+
+// Health check is accessible to anyone, hence it doesn't return anything but a status code;
+// see logs for diagnostics.
+// Invalid parameters are rejected with 400 by the extractor.
+async fn health(
+    State(state): State<AppState>,
+    Query(params): Query<HealthParams>,
+) -> (StatusCode, HeaderMap) {
+    let mut headers = HeaderMap::new();
+    headers.insert("Cache-Control", "no-cache".parse().unwrap());
+
+    // A snapshot served with `--once` is expected to age, and the initial shards may be
+    // close to staleness before the first routine refresh.
+    let in_grace = state.started.elapsed() < Duration::from_secs(state.cfg.startup_grace_sec);
+    let staleness_sec = match params.max_age {
+        Some(age) => Some(age.get() as i64),
+        None if state.cfg.once || in_grace => None,
+        None => Some(state.cfg.shard_staleness_sec),
+    };
+    if check_app_state(&state, staleness_sec).is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, headers);
+    }
+    if let Some(staleness_sec) = staleness_sec {
+        match is_degraded(&state, staleness_sec) {
+            Ok(false) => {}
+            Ok(true) => {
+                headers.insert("X-Health-Degraded", "true".parse().unwrap());
+            }
+            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, headers),
+        }
+    }
+    (StatusCode::OK, headers)
+}
+
+// A shard close to staleness doesn't fail the check, but is reported in advance,
+// see `staleness_warning_fraction`.
+fn is_degraded(state: &AppState, staleness_sec: i64) -> Result<bool, HealthProblem> {
+    if state.cfg.staleness_warning_fraction >= 1.0 {
+        return Ok(false);
+    }
+    let threshold = chrono::Duration::milliseconds(
+        (staleness_sec as f64 * 1000.0 * state.cfg.staleness_warning_fraction) as i64,
+    );
+    for shard_set in state.cache.as_ref() {
+        for (i, shard) in shard_set.shards.iter().enumerate() {
+            if Utc::now() - shard.lock()?.timestamp >= threshold {
+                tracing::warn!(
+                    "A shard is close to staleness (shard {:?}, {:?} shard set)",
+                    i,
+                    shard_set.animal
+                );
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize), serde(deny_unknown_fields))]
+struct AnimalStats {
+    animal: String,
+    // Summed across the shards of the animal
+    facts: usize,
+    oldest_shard_age_sec: i64,
+}
+
+// Public, like `/health`, so it doesn't return anything but numbers.
+async fn stats(
+    State(state): State<AppState>,
+) -> Result<(HeaderMap, Json<Vec<AnimalStats>>), AppError> {
+    let mut headers = HeaderMap::new();
+    headers.insert("Cache-Control", "no-cache".parse().unwrap());
+
+    let now = Utc::now();
+    let mut stats = Vec::with_capacity(state.cache.len());
+    for shard_set in state.cache.as_ref() {
+        let mut facts = 0;
+        let mut oldest = now;
+        for shard in &shard_set.shards {
+            let shard = shard.lock()?;
+            facts += shard.facts.len();
+            oldest = oldest.min(shard.timestamp);
+        }
+        stats.push(AnimalStats {
+            animal: shard_set.animal.to_string(),
+            facts,
+            oldest_shard_age_sec: (now - oldest).num_seconds(),
+        });
+    }
+    Ok((headers, Json(stats)))
+}
+
+// Different probes may tolerate different staleness, hence the threshold is a parameter;
+// `None` skips the staleness check.
+fn check_app_state(state: &AppState, staleness_sec: Option<i64>) -> Result<(), HealthProblem> {
+    if state.cache.len() != state.cfg.animals.len() {
+        tracing::error!("Unexpected number of shard sets");
+        return Err(HealthProblem::UnexpectedState);
+    }
+    for shard_set in state.cache.as_ref() {
+        let shard_num = shard_set.shards.len();
+        if shard_num != state.cfg.shard_num {
+            tracing::error!(
+                "Incorrect number of shards: {:?} ({:?} shard set)",
+                shard_num,
+                shard_set.animal
+            );
+            return Err(HealthProblem::UnexpectedState);
+        }
+        for i in 0..shard_set.shards.len() {
+            let shard = shard_set.shards[i].lock()?;
+            let fact_num = shard.facts.len();
+            if fact_num != state.cfg.shard_size {
+                tracing::error!(
+                    "Incorrect number of facts: {:?} (shard {:?}, {:?} shard set)",
+                    fact_num,
+                    i,
+                    shard_set.animal
+                );
+                return Err(HealthProblem::UnexpectedState);
+            };
+            let stale = staleness_sec.is_some_and(|staleness_sec| {
+                Utc::now() - shard.timestamp >= chrono::Duration::seconds(staleness_sec)
+            });
+            if stale {
+                tracing::error!(
+                    "Stale shard found (shard {:?}, {:?} shard set)",
+                    i,
+                    shard_set.animal
+                );
+                return Err(HealthProblem::StaleShard);
+            };
+        }
+    }
+    Ok(())
+}
+
+// The startup refresh is concurrent, unlike the routine ones, so its duration isn't
+// representative; the estimate is based on the fetch durations instead.
+fn check_refresh_interval(state: &AppState) -> Result<(), AppError> {
+    let Some(interval) = state.cfg.auto_refresh() else {
+        return Ok(());
+    };
+    let estimate: Duration = state
+        .cache
+        .iter()
+        .map(|s| s.fetch_duration().average * s.shards.len() as u32)
+        .sum();
+    if estimate <= interval {
+        return Ok(());
+    }
+    let message = format!(
+        "A refresh of {} animal(s) with {} shard(s) each is expected to take {:.1} sec, \
+        which is longer than `shard_refresh_sec` ({:?}); the shards will be refreshed \
+        less often than configured",
+        state.cache.len(),
+        state.cfg.shard_num,
+        estimate.as_secs_f64(),
+        interval
+    );
+    if state.cfg.strict_refresh_interval {
+        return Err(AppError::InvalidConfig(message));
+    }
+    tracing::warn!("{}", message);
+    Ok(())
+}
+
+// Outcomes of refreshing the shards of each animal
+pub struct RefreshReport(Vec<(Animal, Result<(), AppError>)>);
+
+impl RefreshReport {
+    // Returns the first error, if any
+    pub fn into_result(self) -> Result<(), AppError> {
+        self.0.into_iter().try_for_each(|(_, result)| result)
+    }
+
+    fn log_errors(&self) {
+        for (animal, result) in &self.0 {
+            match result {
+                Ok(()) => (),
+                Err(AppError::CircuitOpen) => {
+                    tracing::debug!("Circuit breaker is open ({:?} shard set)", animal)
+                }
+                Err(AppError::RateLimited(delay)) => tracing::warn!(
+                    "Fact provider is rate limited, retry after {:?} ({:?} shard set)",
+                    delay,
+                    animal
+                ),
+                Err(e) => tracing::error!("Fact fetching error ({:?} shard set): {:?}", animal, e),
+            }
+        }
+    }
+}
+
+// For the sake of simplicity each shard contains all facts from a signle response.
+// Up to `concurrency` requests to fact providers are sent at once; it's mostly
+// the startup that benefits from concurrency, routine refreshes aren't urgent.
+// A failure concerning one animal doesn't prevent the others from being refreshed.
+pub async fn refresh_shards(state: &AppState, concurrency: usize) -> RefreshReport {
+    let _guard = state.refresh_lock.lock().await;
+    tracing::debug!("Fetching animal facts");
+    let start = Instant::now();
+    let mut outcomes: Vec<_> = state.cache.iter().map(|s| (s.animal, Ok(()))).collect();
+    let mut sets = vec![];
+    for (i, shard_set) in state.cache.iter().enumerate() {
+        if shard_set.breaker().allows_requests() {
+            sets.push((i, shard_set.shards.as_slice()));
+        } else {
+            outcomes[i].1 = Err(AppError::CircuitOpen);
+        }
+    }
+    let shards = state.scheduler.refresh_order(&sets);
+
+    let mut results = stream::iter(shards)
+        .map(|(i, j)| async move { (i, refresh_shard(state, &state.cache[i], j).await) })
+        .buffer_unordered(concurrency.max(1));
+    while let Some((i, result)) = results.next().await {
+        if let (Err(e), Ok(())) = (result, &outcomes[i].1) {
+            outcomes[i].1 = Err(e);
+        }
+    }
+
+    // The breakers concern the providers, which readers don't request.
+    let breakers = outcomes.iter().zip(state.cache.iter());
+    for ((_, result), shard_set) in breakers.filter(|_| !state.is_store_reader()) {
+        let mut breaker = shard_set.breaker();
+        match result {
+            Ok(()) => breaker.record_success(),
+            // The provider has told when to come back, which is honored instead of the breaker.
+            Err(AppError::CircuitOpen | AppError::RateLimited(Some(_))) => (),
+            Err(_) => {
+                if breaker.record_failure() {
+                    tracing::warn!(
+                        "Circuit breaker opened, facts won't be fetched for {} sec ({:?} shard set)",
+                        state.cfg.breaker_cooldown_sec,
+                        shard_set.animal
+                    );
+                }
+            }
+        }
+    }
+
+    let average = {
+        let mut duration = state
+            .refresh_duration
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        duration.record(start.elapsed());
+        duration.average
+    };
+    // The refresh time isn't included into the interval, so slow refreshes make shards older
+    // than expected (and may exhaust the staleness threshold).
+    let interval = state.cfg.auto_refresh();
+    if let Some(interval) = interval.filter(|interval| average > *interval) {
+        tracing::warn!(
+            "Refreshes take {:.1} sec on average, which is longer than the refresh interval \
+            ({:?}); consider increasing `shard_refresh_sec`",
+            average.as_secs_f64(),
+            interval
+        );
+    }
+    RefreshReport(outcomes)
+}
+
+// The spans show where the time of a refresh goes; errors are reported by the caller.
+#[tracing::instrument(name = "refresh_shard", skip_all, fields(animal = %shard_set.animal, shard = i))]
+async fn refresh_shard(state: &AppState, shard_set: &ShardSet, i: usize) -> Result<(), AppError> {
+    let start = Instant::now();
+    let new_shard = match &state.store {
+        Some(store) if state.is_store_reader() => load_shard(store.as_ref(), shard_set, i).await,
+        _ => fetch_shard_retrying(state, shard_set, i).await,
+    };
+    shard_set
+        .fetch_duration
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .record(start.elapsed());
+    let new_shard = new_shard?;
+    check_total_fact_bytes(state, shard_set, i, &new_shard)?;
+    if let (Some(store), true) = (&state.store, state.cfg.redis_writer) {
+        // The local shard is fine anyway, and the store will be updated by the next refresh.
+        if let Err(e) = store
+            .save(shard_set.animal, i, StoredShard::from(&new_shard))
+            .await
+        {
+            tracing::error!(
+                "Failed to save a shard (shard {:?}, {:?} shard set): {:?}",
+                i,
+                shard_set.animal,
+                e
+            );
+        }
+    }
+    *shard_set.shards[i].lock()? = new_shard;
+    Ok(())
+}
+
+// The facts take the bulk of the memory, and the providers can't be really trusted, hence
+// the cap (see `max_total_fact_bytes`). Other shards may be refreshed concurrently, so the total
+// is an estimate; the shard locks aren't held together to avoid deadlocks.
+fn check_total_fact_bytes(
+    state: &AppState,
+    shard_set: &ShardSet,
+    i: usize,
+    new_shard: &Shard,
+) -> Result<(), AppError> {
+    let Some(limit) = state.cfg.max_total_fact_bytes else {
+        return Ok(());
+    };
+    let mut total = new_shard.fact_bytes;
+    for other_set in state.cache.as_ref() {
+        for (j, shard) in other_set.shards.iter().enumerate() {
+            if !(std::ptr::eq(other_set, shard_set) && j == i) {
+                total += shard.lock()?.fact_bytes;
+            }
+        }
+    }
+    if total <= limit {
+        return Ok(());
+    }
+    tracing::warn!(
+        "The refreshed shard isn't stored, the facts would take {} bytes, more than \
+        `max_total_fact_bytes` ({}) (shard {:?}, {:?} shard set)",
+        total,
+        limit,
+        i,
+        shard_set.animal
+    );
+    Err(AppError::InvalidData(format!(
+        "The facts would exceed `max_total_fact_bytes` ({} bytes)",
+        limit
+    )))
+}
+
+// The shard hasn't been saved yet if the writer hasn't refreshed it.
+async fn load_shard(
+    store: &dyn ShardStore,
+    shard_set: &ShardSet,
+    i: usize,
+) -> Result<Shard, AppError> {
+    match store.load(shard_set.animal, i).await? {
+        Some(stored) => Ok(Shard::from(stored)),
+        None => Err(AppError::NoData),
+    }
+}
+
+// An unparsable body may be a truncated response rather than a change of the schema,
+// so the shard is fetched once more; unlike HTTP errors, it isn't retried by the client.
+async fn fetch_shard_retrying(
+    state: &AppState,
+    shard_set: &ShardSet,
+    i: usize,
+) -> Result<Shard, AppError> {
+    let e = match fetch_shard(state, shard_set, i).await {
+        Err(AppError::JsonParsingError(e)) => e,
+        result => return result,
+    };
+    tracing::warn!(
+        "Unparsable {} facts received (shard {:?}), retrying: {:?}",
+        shard_set.animal,
+        i,
+        e
+    );
+    let result = fetch_shard(state, shard_set, i).await;
+    match &result {
+        Ok(_) => tracing::info!(
+            "The retry of {} facts (shard {:?}) succeeded",
+            shard_set.animal,
+            i
+        ),
+        Err(e) => tracing::warn!(
+            "The retry of {} facts (shard {:?}) failed: {:?}",
+            shard_set.animal,
+            i,
+            e
+        ),
+    }
+    result
+}
+
+// The first provider returning a valid batch wins; if all of them fail, the last error is
+// returned (and reported by the caller). The mirrors of a provider are tried from the fastest one.
+async fn fetch_shard(state: &AppState, shard_set: &ShardSet, i: usize) -> Result<Shard, AppError> {
+    let mut last_error = None;
+    for (n, (provider, latencies)) in shard_set
+        .providers
+        .iter()
+        .zip(&shard_set.latencies)
+        .enumerate()
+    {
+        // The delay requested by the provider replaces the breaker's cooldown.
+        if let Some(delay) = shard_set.rate_limit(n) {
+            tracing::debug!(
+                "Provider #{} of {} facts is rate limited for {:?}, skipping it",
+                n,
+                shard_set.animal,
+                delay
+            );
+            last_error = Some(AppError::RateLimited(Some(delay)));
+            continue;
+        }
+        for mirror in latencies.order() {
+            if let Some(e) = last_error.take() {
+                // URLs aren't logged as they may contain API keys
+                tracing::warn!(
+                    "A provider of {} facts failed, trying provider #{} mirror #{}: {:?}",
+                    shard_set.animal,
+                    n,
+                    mirror,
+                    e
+                );
+            }
+            let start = Instant::now();
+            let fetch_span = tracing::info_span!(
+                "fetch_raw_facts",
+                animal = %shard_set.animal,
+                shard = i,
+                provider = n,
+                mirror
+            );
+            let shard = match fetch_raw_facts(
+                &state.client,
+                provider,
+                mirror,
+                state.cfg.shard_size,
+                state.cfg.max_response_bytes,
+            )
+            .instrument(fetch_span)
+            .await
+            {
+                Ok(body) => {
+                    tracing::info_span!("validate_batch", animal = %shard_set.animal, shard = i)
+                        .in_scope(|| {
+                            validate_batch(
+                                body,
+                                &provider.format,
+                                state.cfg.shard_size,
+                                &Normalization::from(&state.cfg),
+                            )
+                        })
+                }
+                Err(e) => Err(e),
+            };
+            match shard {
+                Ok(shard) => {
+                    latencies.record(mirror, start.elapsed());
+                    return Ok(shard);
+                }
+                Err(e) => {
+                    latencies.record_failure(mirror);
+                    // A 429 concerns the provider rather than the mirror,
+                    // so its other mirrors aren't requested either.
+                    let rate_limited = matches!(e, AppError::RateLimited(_));
+                    if let AppError::RateLimited(Some(delay)) = e {
+                        // The delay is capped, but in case the clock still overflows,
+                        // the provider is retried on the next run instead of crashing.
+                        *shard_set.retry_at[n]
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner) =
+                            Instant::now().checked_add(delay);
+                    }
+                    last_error = Some(e);
+                    if rate_limited {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    // The providers file doesn't allow empty lists, so the error is always set.
+    Err(last_error.unwrap_or(AppError::NoData))
+}
+
+// Due to lack of time, I have to limit myself to basic tests.
+// Ideally, fact validators deserve thorough testing as they work with third-party data.
+// Most tests need both animals; builds with a single one are covered by `single_provider_test`.
+#[cfg(all(test, feature = "dog", feature = "cat"))]
+mod test {
+    use crate::*;
+
+    use axum::http::{
+        header::{AUTHORIZATION, RETRY_AFTER},
+        HeaderValue, StatusCode,
+    };
+    use axum_test::{TestResponse, TestServer};
+    use breaker::BreakerState;
+    use config::{ErrorFormat, RefreshOrder, ShardSelection};
+    use serde_json::Value;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    fn get_test_config(animals: Vec<Animal>) -> ServerConfig {
+        ServerConfig::default()
+            .with_shard_num(2)
+            .with_shard_staleness_sec(1)
+            .with_verbosity(tracing::Level::TRACE)
+            .with_animals(animals.into_iter().map(AnimalSpec::from).collect())
+    }
+
+    async fn set_up_test_server(cfg: ServerConfig) -> (TestServer, AppState) {
+        let state = init_state(cfg).unwrap();
+        refresh_shards(&state, 1).await.into_result().unwrap();
+        if check_app_state(&state, Some(state.cfg.shard_staleness_sec)).is_err() {
+            panic!("Invalid initial state");
+        }
+        let app = build_router(state.clone()).into_make_service();
+        (TestServer::new(app).unwrap(), state)
+    }
+
+    // Serves `app` on a random local port, e.g. to imitate a fact provider.
+    async fn spawn_mock_server(app: Router) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        format!("http://{}", addr)
+    }
+
+    async fn get_fact(server: &TestServer, expected_animals: &HashSet<String>) -> FactResponse {
+        check_fact(server.get("/fact").await, expected_animals)
+    }
+
+    // Unknown fields are rejected by `FactResponse` in tests.
+    fn check_fact(response: TestResponse, expected_animals: &HashSet<String>) -> FactResponse {
+        assert_eq!(response.status_code(), StatusCode::OK);
+        let parsed_response = response.json::<FactResponse>();
+        assert!(
+            expected_animals.contains(&parsed_response.animal),
+            "Incorrect animal in the response: {:?}",
+            parsed_response
+        );
+        // Currrently all we know is that each fact is a string, but further validation can be added later
+        assert!(
+            !parsed_response.fact.is_empty(),
+            "Incorrect fact in the response: {:?}",
+            parsed_response
+        );
+        parsed_response
+    }
+
+    async fn get_health(server: &TestServer) {
+        let response = server.get("/health").await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_shard_staleness() {
+        let cfg = get_test_config(vec![Animal::Cat]).with_shard_num(1);
+        let (_, state) = set_up_test_server(cfg).await;
+        let set_age = |age: chrono::Duration| {
+            state.cache[0].shards[0].lock().unwrap().timestamp = Utc::now() - age;
+        };
+        // Sub-second ages aren't rounded
+        set_age(chrono::Duration::milliseconds(900));
+        assert!(check_app_state(&state, Some(1)).is_ok());
+        set_age(chrono::Duration::milliseconds(1100));
+        assert!(matches!(
+            check_app_state(&state, Some(1)),
+            Err(HealthProblem::StaleShard)
+        ));
+        // Even the default timestamp is valid, it's just stale
+        state.cache[0].shards[0].lock().unwrap().timestamp = Shard::default().timestamp;
+        assert!(matches!(
+            check_app_state(&state, Some(1)),
+            Err(HealthProblem::StaleShard)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_health_degraded() {
+        let cfg = get_test_config(vec![Animal::Cat]).with_shard_staleness_sec(10);
+        let (server, state) = set_up_test_server(cfg).await;
+        let set_age = |age: i64| {
+            for shard in &state.cache[0].shards {
+                shard.lock().unwrap().timestamp = Utc::now() - chrono::Duration::seconds(age);
+            }
+        };
+        let check = |status: StatusCode, degraded: bool| {
+            let server = &server;
+            async move {
+                let response = server.get("/health").await;
+                assert_eq!(response.status_code(), status);
+                assert_eq!(
+                    response.headers().get("X-Health-Degraded").is_some(),
+                    degraded
+                );
+            }
+        };
+
+        set_age(1);
+        check(StatusCode::OK, false).await;
+        // Past 80% of the staleness threshold
+        set_age(9);
+        check(StatusCode::OK, true).await;
+        set_age(11);
+        check(StatusCode::INTERNAL_SERVER_ERROR, false).await;
+
+        let cfg = get_test_config(vec![Animal::Cat])
+            .with_shard_staleness_sec(10)
+            .with_staleness_warning_fraction(1.0);
+        let (server, state) = set_up_test_server(cfg).await;
+        for shard in &state.cache[0].shards {
+            shard.lock().unwrap().timestamp = Utc::now() - chrono::Duration::seconds(9);
+        }
+        let response = server.get("/health").await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert!(response.headers().get("X-Health-Degraded").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_health_max_age() {
+        let cfg = get_test_config(vec![Animal::Cat]).with_shard_staleness_sec(10);
+        let (server, state) = set_up_test_server(cfg).await;
+        for shard in &state.cache[0].shards {
+            shard.lock().unwrap().timestamp = Utc::now() - chrono::Duration::seconds(30);
+        }
+        let probe = |max_age: Option<&str>| {
+            let mut request = server.get("/health");
+            if let Some(max_age) = max_age {
+                request = request.add_query_param("max_age", max_age);
+            }
+            async move { request.await.status_code() }
+        };
+
+        // The configured threshold applies by default
+        assert_eq!(probe(None).await, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(probe(Some("60")).await, StatusCode::OK);
+        assert_eq!(probe(Some("20")).await, StatusCode::INTERNAL_SERVER_ERROR);
+        for invalid in ["0", "-5", "1.5", "abc", ""] {
+            assert_eq!(
+                probe(Some(invalid)).await,
+                StatusCode::BAD_REQUEST,
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    // Checks of random selections are repeated; exact selections can be checked
+    // with a seeded RNG instead, see `test_seeded_rng`.
+    const REQUEST_NUM: u8 = 10;
+
+    async fn tets_api_inner(animals: Vec<Animal>) {
+        let animal_set: HashSet<_> = animals.iter().map(|a| a.to_string()).collect();
+        let (server, _) = set_up_test_server(get_test_config(animals)).await;
+        for _ in 0..REQUEST_NUM {
+            get_fact(&server, &animal_set).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_api() {
+        tets_api_inner(vec![Animal::Cat]).await;
+        tets_api_inner(vec![Animal::Dog]).await;
+        tets_api_inner(vec![Animal::Cat, Animal::Dog]).await;
+    }
+
+    const UPDATE_NUM: u8 = 10;
+
+    // If need be, one can split this test into fast (without staleness checks and sleeping)
+    // and slow versions.
+    #[tokio::test]
+    async fn test_shard_refreshing() {
+        let animals = vec![Animal::Cat];
+        let animal_set: HashSet<_> = animals.iter().map(|a| a.to_string()).collect();
+        let (server, state) = set_up_test_server(get_test_config(animals)).await;
+
+        for _ in 0..UPDATE_NUM {
+            refresh_shards(&state, 1).await.into_result().unwrap();
+            get_health(&server).await;
+            get_fact(&server, &animal_set).await;
+            get_health(&server).await;
+            sleep(Duration::from_secs(state.cfg.shard_staleness_sec as u64)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_size_limit() {
+        let body = "x".repeat(1024);
+        let url =
+            spawn_mock_server(Router::new().route("/", get(move || async move { body }))).await;
+        let client = reqwest::Client::new();
+
+        match fetch_url(&client, &url, 1023).await {
+            Err(AppError::ResponseTooLarge(1023)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert_eq!(fetch_url(&client, &url, 1024).await.unwrap().len(), 1024);
+    }
+
+    #[tokio::test]
+    async fn test_validate_config() {
+        // Nothing is fetched, so the fake provider isn't called
+        let cfg = get_test_config(vec![Animal::Cat, Animal::Dog])
+            .with_shard_staleness_sec(10)
+            .with_validate_config(true);
+        run(cfg.clone()).await.unwrap();
+        assert!(animals::fake::calls().is_empty());
+
+        let invalid = cfg.clone().with_shard_num(0);
+        assert!(matches!(
+            run(invalid).await,
+            Err(AppError::InvalidConfig(_))
+        ));
+        let missing_file = cfg.with_providers_file(Some("/nonexistent/providers.json".into()));
+        assert!(matches!(
+            run(missing_file).await,
+            Err(AppError::InvalidConfig(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_print_sample() {
+        let cfg = get_test_config(vec![Animal::Cat, Animal::Dog]).with_shard_size(5);
+        let state = init_state(cfg).unwrap();
+        let mut out = vec![];
+        print_sample(&state, 3, &mut out).await.unwrap();
+        let out = String::from_utf8(out).unwrap();
+        for animal in ["cat", "dog"] {
+            let prefix = format!("{}: ", animal);
+            assert_eq!(out.lines().filter(|l| l.starts_with(&prefix)).count(), 3);
+        }
+        // A full batch is fetched per animal
+        assert_eq!(animals::fake::calls().len(), 2);
+
+        // The failure of cats doesn't prevent printing dog facts
+        animals::fake::script(Animal::Cat, vec![Err(AppError::NoData)]);
+        let mut out = vec![];
+        assert!(matches!(
+            print_sample(&state, 10, &mut out).await,
+            Err(AppError::NoData)
+        ));
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), state.cfg.shard_size);
+        assert!(out.lines().all(|l| l.starts_with("dog: ")));
+
+        // E.g. the output is piped into `head`
+        struct ClosedPipe;
+        impl std::io::Write for ClosedPipe {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        assert!(matches!(
+            print_sample(&state, 3, &mut ClosedPipe).await,
+            Err(AppError::OutputError(e)) if e.kind() == std::io::ErrorKind::BrokenPipe
+        ));
+    }
+
+    #[test]
+    fn test_pool_settings() {
+        let default = PoolSettings::from(&ServerConfig::default());
+        assert_eq!(default.max_idle_per_host, None);
+        assert_eq!(default.idle_timeout, Some(Duration::from_secs(90)));
+
+        let cfg = get_test_config(vec![Animal::Cat])
+            .with_pool_max_idle_per_host(Some(2))
+            .with_pool_idle_timeout_sec(0);
+        assert_eq!(
+            PoolSettings::from(&cfg),
+            PoolSettings {
+                max_idle_per_host: Some(2),
+                idle_timeout: None,
+            }
+        );
+        assert!(init_state(cfg.with_pool_idle_timeout_sec(5)).is_ok());
+    }
+
+    // Writes a providers file unique to the test
+    fn write_providers_file(name: &str, json: serde_json::Value) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "shuttle-test-providers-{}-{}.json",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, json.to_string()).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_fallback_provider() {
+        let failing = spawn_mock_server(
+            Router::new().route("/", get(|| async { StatusCode::INTERNAL_SERVER_ERROR })),
+        )
+        .await;
+        let invalid =
+            spawn_mock_server(Router::new().route("/", get(|| async { "[{\"text\": 1}]" }))).await;
+        let healthy = spawn_mock_server(Router::new().route(
+            "/",
+            get(|Query(params): Query<HashMap<String, usize>>| async move {
+                let facts: Vec<_> = (0..params["amount"])
+                    .map(|i| serde_json::json!({ "text": format!("secondary fact #{}", i) }))
+                    .collect();
+                Json(facts)
+            }),
+        ))
+        .await;
+        let path = write_providers_file(
+            "fallback",
+            serde_json::json!({"cat": [
+                {"url": failing},
+                {"url": invalid},
+                {"url": format!("{}/?amount={{shard_size}}", healthy)},
+            ]}),
+        );
+        let cfg = get_test_config(vec![Animal::Cat])
+            .with_shard_size(3)
+            .with_providers_file(Some(path.clone()));
+        let (_, state) = set_up_test_server(cfg).await;
+        for shard in &state.cache[0].shards {
+            assert_eq!(
+                shard.lock().unwrap().facts,
+                vec![
+                    "secondary fact #0",
+                    "secondary fact #1",
+                    "secondary fact #2"
+                ]
+            );
+        }
+        // The built-in provider isn't used once a list is given
+        assert!(animals::fake::calls().is_empty());
+        std::fs::remove_file(path).unwrap();
+
+        let path = write_providers_file(
+            "all-failing",
+            serde_json::json!({"cat": [{"url": failing}, {"url": invalid}]}),
+        );
+        let cfg = get_test_config(vec![Animal::Cat]).with_providers_file(Some(path.clone()));
+        let state = init_state(cfg).unwrap();
+        match refresh_shards(&state, 1).await.into_result() {
+            Err(AppError::JsonParsingError(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // Returns the URL of the mirror and the number of requests it has received
+    async fn spawn_mirror(latency: Duration) -> (String, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let url = spawn_mock_server(Router::new().route(
+            "/",
+            get(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    sleep(latency).await;
+                    Json(serde_json::json!([{ "text": "mirrored fact" }]))
+                }
+            }),
+        ))
+        .await;
+        (url, hits)
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let url = spawn_mock_server(Router::new().route(
+            "/",
+            get(move || async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, "120")])
+            }),
+        ))
+        .await;
+        let path = write_providers_file("rate_limit", serde_json::json!({"cat": [{"url": url}]}));
+        let cfg = get_test_config(vec![Animal::Cat]).with_providers_file(Some(path.clone()));
+        let state = init_state(cfg.clone()).unwrap();
+
+        // Unlike other failures, the rate limiting doesn't open the breaker
+        for _ in 0..state.cfg.breaker_failure_threshold {
+            let report = refresh_shards(&state, 1).await;
+            let Err(AppError::RateLimited(Some(delay))) = report.0[0].1 else {
+                panic!("Unexpected outcome: {:?}", report.0[0].1);
+            };
+            assert!(delay > Duration::from_secs(110) && delay <= Duration::from_secs(120));
+        }
+        // The other shard and the later refreshes haven't requested the provider
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        assert_eq!(state.cache[0].breaker().state(), BreakerState::Closed);
+
+        // The provider is requested once the delay is over
+        *state.cache[0].retry_at[0].lock().unwrap() = Some(tokio::time::Instant::now());
+        refresh_shards(&state, 1).await;
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        std::fs::remove_file(path).unwrap();
+
+        // The other mirrors of the provider aren't requested after a 429
+        let (mirror, mirror_hits) = spawn_mirror(Duration::ZERO).await;
+        let path = write_providers_file(
+            "rate_limit_mirrors",
+            serde_json::json!({"cat": [{"url": url, "mirrors": [mirror]}]}),
+        );
+        let state = init_state(cfg.with_providers_file(Some(path.clone()))).unwrap();
+        assert!(matches!(
+            refresh_shards(&state, 1).await.into_result(),
+            Err(AppError::RateLimited(Some(_)))
+        ));
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        assert_eq!(mirror_hits.load(Ordering::SeqCst), 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mirror_selection() {
+        let (slow, slow_hits) = spawn_mirror(Duration::from_millis(50)).await;
+        let (fast, fast_hits) = spawn_mirror(Duration::ZERO).await;
+        let path = write_providers_file(
+            "mirrors",
+            serde_json::json!({"cat": [{"url": slow, "mirrors": [fast]}]}),
+        );
+        let cfg = get_test_config(vec![Animal::Cat])
+            .with_shard_num(1)
+            .with_shard_size(1)
+            .with_providers_file(Some(path.clone()));
+        let state = init_state(cfg).unwrap();
+        let refreshes = 50;
+        for _ in 0..refreshes {
+            refresh_shards(&state, 1).await.into_result().unwrap();
+        }
+        let (slow_hits, fast_hits) = (
+            slow_hits.load(Ordering::SeqCst),
+            fast_hits.load(Ordering::SeqCst),
+        );
+        assert_eq!(slow_hits + fast_hits, refreshes);
+        assert!(
+            fast_hits > refreshes * 3 / 4,
+            "{} of {}",
+            fast_hits,
+            refreshes
+        );
+        // Besides the first measurement, the slow mirror is probed from time to time
+        assert!(slow_hits >= 3, "{} of {}", slow_hits, refreshes);
+
+        let latencies = state.cache[0].latencies[0].snapshot();
+        assert!(latencies[0].average > latencies[1].average);
+        let (_, body) = metrics::metrics(State(state)).await.unwrap();
+        assert!(body.contains(
+            "provider_latency_seconds{animal=\"cat\",provider=\"0\",mirror=\"1\",stat=\"average\"}"
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compressed_response() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let facts: Vec<_> = (0..3)
+            .map(|i| serde_json::json!({ "text": format!("compressed fact #{}", i) }))
+            .collect();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(serde_json::to_string(&facts).unwrap().as_bytes())
+            .unwrap();
+        let body = encoder.finish().unwrap();
+        let url = spawn_mock_server(Router::new().route(
+            "/",
+            get(move |headers: HeaderMap| async move {
+                let accepted = headers
+                    .get(axum::http::header::ACCEPT_ENCODING)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default();
+                if !accepted.contains("gzip") {
+                    return StatusCode::NOT_ACCEPTABLE.into_response();
+                }
+                ([(axum::http::header::CONTENT_ENCODING, "gzip")], body).into_response()
+            }),
+        ))
+        .await;
+        let path =
+            write_providers_file("compressed", serde_json::json!({ "cat": [{ "url": url }] }));
+        let cfg = get_test_config(vec![Animal::Cat])
+            .with_shard_size(3)
+            .with_providers_file(Some(path.clone()));
+        let (_, state) = set_up_test_server(cfg).await;
+        assert_eq!(
+            state.cache[0].shards[0].lock().unwrap().facts,
+            vec![
+                "compressed fact #0",
+                "compressed fact #1",
+                "compressed fact #2"
+            ]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fact_exclusion() {
+        let animals = vec![Animal::Cat];
+        let animal_set: HashSet<_> = animals.iter().map(|a| a.to_string()).collect();
+        let cfg = get_test_config(animals)
+            .with_shard_num(1)
+            .with_shard_size(2);
+        let (server, state) = set_up_test_server(cfg).await;
+        let facts = state.cache[0].shards[0].lock().unwrap().facts.clone();
+        assert_ne!(facts[0], facts[1]);
+
+        let exclude = format!("{},unknown", FactId::of(&facts[0]));
+        for _ in 0..REQUEST_NUM {
+            let request = server.get("/fact").add_query_param("exclude", &exclude);
+            let response = check_fact(request.await, &animal_set);
+            assert_eq!(response.fact, facts[1]);
+        }
+
+        // All the facts are excluded, so any of them is acceptable.
+        let exclude = format!("{},{}", FactId::of(&facts[0]), FactId::of(&facts[1]));
+        for _ in 0..REQUEST_NUM {
+            let request = server.get("/fact").add_query_param("exclude", &exclude);
+            let response = check_fact(request.await, &animal_set);
+            assert!(facts.contains(&response.fact));
+        }
+    }
+
+    #[test]
+    fn test_fact_id() {
+        assert_eq!(FactId::of("").to_string(), "cbf29ce484222325");
+        assert_eq!(FactId::of("a cat fact"), FactId::of("a cat fact"));
+        assert_ne!(FactId::of("a cat fact"), FactId::of("a dog fact"));
+        let id = FactId::of("a cat fact");
+        assert_eq!(id.to_string().parse::<FactId>().unwrap(), id);
+    }
+
+    #[tokio::test]
+    async fn test_content_digest() {
+        let cfg = get_test_config(vec![Animal::Cat]).with_shard_num(1);
+        let (server, state) = set_up_test_server(cfg).await;
+        label_shards(&state);
+
+        let response = server.get("/fact").await;
+        let body = response.text();
+        assert_eq!(
+            response.header(CONTENT_DIGEST),
+            FactId::of(&body).to_string()
+        );
+        assert_eq!(response.header("Content-Length"), body.len().to_string());
+        // The body isn't affected
+        check_fact(response, &HashSet::from(["cat".to_string()]));
+
+        // Identical payloads get identical digests, unlike different ones
+        let again = server.get("/fact").await;
+        assert_eq!(again.text(), body);
+        assert_eq!(again.header(CONTENT_DIGEST), FactId::of(&body).to_string());
+        let with_id = server.get("/fact").add_query_param("with_id", true).await;
+        assert_ne!(
+            with_id.header(CONTENT_DIGEST),
+            FactId::of(&body).to_string()
+        );
+        assert_eq!(
+            with_id.header(CONTENT_DIGEST),
+            FactId::of(&with_id.text()).to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shared_client() {
+        let state = init_state(get_test_config(vec![Animal::Cat, Animal::Dog])).unwrap();
+        refresh_shards(&state, 1).await.into_result().unwrap();
+        refresh_shards(&state, 1).await.into_result().unwrap();
+
+        let calls = animals::fake::calls();
+        assert_eq!(calls.len(), 2 * 2 * state.cfg.shard_num);
+        let client = &state.client as *const reqwest::Client;
+        assert!(calls.iter().all(|c| c.client == client));
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let (server, state) = set_up_test_server(get_test_config(vec![Animal::Cat])).await;
+        let response = server.get("/metrics").await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        let body = response.text();
+
+        // All the fake facts are short
+        let fact_num = state.cfg.shard_num * state.cfg.shard_size;
+        let expected = [
+            format!("fact_length{{animal=\"cat\",range=\"0-50\"}} {}", fact_num),
+            "fact_length{animal=\"cat\",range=\"200+\"} 0".to_string(),
+        ];
+        for line in expected {
+            assert!(
+                body.lines().any(|l| l == line),
+                "{:?} not found in {}",
+                line,
+                body
+            );
+        }
+    }
+
+    // Collects the logs written while the guard returned by `capture_logs` is alive
+    #[derive(Clone, Default)]
+    struct LogCapture(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogCapture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LogCapture {
+        fn take(&self) -> String {
+            String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+        }
+    }
+
+    fn capture_logs() -> (LogCapture, tracing::subscriber::DefaultGuard) {
+        let logs = LogCapture::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::WARN)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        (logs, tracing::subscriber::set_default(subscriber))
+    }
+
+    #[tokio::test]
+    async fn test_refresh_spans() {
+        use tracing_subscriber::fmt::format::FmtSpan;
+
+        let logs = LogCapture::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::INFO)
+            .with_ansi(false)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let state = init_state(get_test_config(vec![Animal::Cat, Animal::Dog])).unwrap();
+        // The retry fails as well
+        animals::fake::script(
+            Animal::Dog,
+            vec![Ok("invalid".to_string()), Ok("invalid".to_string())],
+        );
+        // The spans don't change the outcome of a failed fetch
+        let report = refresh_shards(&state, 1).await;
+        assert!(matches!(report.0[1].1, Err(AppError::JsonParsingError(_))));
+        let logs = logs.take();
+        for animal in ["cat", "dog"] {
+            for shard in 0..state.cfg.shard_num {
+                let fields = format!("animal={} shard={}", animal, shard);
+                assert!(
+                    logs.contains(&format!("refresh_shard{{{}}}", fields)),
+                    "{}",
+                    logs
+                );
+                let fetch = format!(
+                    "fetch_raw_facts{{{} provider=0 mirror=0}}: shuttle_test: close",
+                    fields
+                );
+                assert!(logs.contains(&fetch), "{}", logs);
+                let validate = format!("validate_batch{{{}}}: shuttle_test: close", fields);
+                assert!(logs.contains(&validate), "{}", logs);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_total_fact_bytes() {
+        // Fake facts take 11 bytes (e.g. "cat fact #0"), 176 in total
+        let cfg = get_test_config(vec![Animal::Cat, Animal::Dog])
+            .with_shard_size(4)
+            .with_max_total_fact_bytes(Some(200));
+        let state = init_state(cfg).unwrap();
+        refresh_shards(&state, 1).await.into_result().unwrap();
+        let facts = state.cache[0].shards[0].lock().unwrap().facts.clone();
+
+        let (logs, _guard) = capture_logs();
+        let huge: Vec<_> = (0..4)
+            .map(|i| serde_json::json!({ "text": format!("{} {}", i, "x".repeat(100)) }))
+            .collect();
+        animals::fake::script(Animal::Cat, vec![Ok(serde_json::to_string(&huge).unwrap())]);
+        let report = refresh_shards(&state, 1).await;
+        assert!(matches!(report.0[0].1, Err(AppError::InvalidData(_))));
+        assert!(report.0[1].1.is_ok());
+        // The old facts survive, while the other shards are refreshed as usual
+        assert_eq!(state.cache[0].shards[0].lock().unwrap().facts, facts);
+        assert!(logs.take().contains("`max_total_fact_bytes` (200)"));
+
+        // A shard can be replaced with a bigger one if the total fits
+        let bigger: Vec<_> = (0..4)
+            .map(|i| serde_json::json!({ "text": format!("{} {}", i, "x".repeat(10)) }))
+            .collect();
+        animals::fake::script(
+            Animal::Cat,
+            vec![Ok(serde_json::to_string(&bigger).unwrap())],
+        );
+        refresh_shards(&state, 1).await.into_result().unwrap();
+        assert!(state.cache[0].shards[0].lock().unwrap().facts[0].ends_with("xxx"));
+    }
+
+    #[tokio::test]
+    async fn test_parse_retry() {
+        let state = init_state(get_test_config(vec![Animal::Dog]).with_shard_num(1)).unwrap();
+        let (logs, _guard) = capture_logs();
+        animals::fake::script(Animal::Dog, vec![Ok("[{\"truncated".to_string())]);
+        refresh_shards(&state, 1).await.into_result().unwrap();
+        assert_eq!(animals::fake::calls().len(), 2);
+        assert_eq!(
+            state.cache[0].shards[0].lock().unwrap().facts.len(),
+            state.cfg.shard_size
+        );
+        assert!(logs.take().contains("retrying"));
+
+        // The old facts are kept if the retry fails as well
+        let facts = state.cache[0].shards[0].lock().unwrap().facts.clone();
+        animals::fake::script(
+            Animal::Dog,
+            vec![Ok("invalid".to_string()), Ok("invalid".to_string())],
+        );
+        let report = refresh_shards(&state, 1).await;
+        assert!(matches!(report.0[0].1, Err(AppError::JsonParsingError(_))));
+        assert_eq!(animals::fake::calls().len(), 2);
+        assert_eq!(state.cache[0].shards[0].lock().unwrap().facts, facts);
+        assert!(logs
+            .take()
+            .contains("The retry of dog facts (shard 0) failed"));
+
+        // Other errors aren't retried
+        animals::fake::script(Animal::Dog, vec![Err(AppError::NoData)]);
+        assert!(refresh_shards(&state, 1).await.into_result().is_err());
+        assert_eq!(animals::fake::calls().len(), 1);
+    }
+
+    // The time is paused, so the fake delays are measured exactly.
+    #[tokio::test(start_paused = true)]
+    async fn test_refresh_duration() {
+        let cfg = get_test_config(vec![Animal::Cat, Animal::Dog])
+            .with_shard_refresh_sec(Duration::from_secs(1));
+        let state = init_state(cfg).unwrap();
+        let (logs, _guard) = capture_logs();
+        let fetch_num = state.cfg.shard_num as u32 * 2;
+
+        animals::fake::set_delay(Duration::from_millis(100));
+        refresh_shards(&state, 1).await.into_result().unwrap();
+        let refresh_duration = state.refresh_duration();
+        assert_eq!(refresh_duration.count, 1);
+        assert!(refresh_duration.last >= Duration::from_millis(100) * fetch_num);
+        assert!(refresh_duration.last < Duration::from_millis(110) * fetch_num);
+        for shard_set in state.cache.iter() {
+            let fetch_duration = shard_set.fetch_duration();
+            assert_eq!(fetch_duration.count, state.cfg.shard_num as u64);
+            assert!(fetch_duration.last >= Duration::from_millis(100));
+            assert!(fetch_duration.last < Duration::from_millis(110));
+        }
+        assert_eq!(logs.take(), "");
+
+        // A single slow refresh isn't worth a warning, but regular ones are.
+        animals::fake::set_delay(Duration::from_millis(400));
+        refresh_shards(&state, 1).await.into_result().unwrap();
+        assert!(state.refresh_duration().last > Duration::from_secs(1));
+        assert!(state.refresh_duration().average < Duration::from_secs(1));
+        assert_eq!(logs.take(), "");
+        for _ in 0..3 {
+            refresh_shards(&state, 1).await.into_result().unwrap();
+        }
+        assert!(state.refresh_duration().average > Duration::from_secs(1));
+        assert!(logs
+            .take()
+            .contains("consider increasing `shard_refresh_sec`"));
+
+        let (_, body) = metrics::metrics(State(state.clone())).await.unwrap();
+        for prefix in [
+            "refresh_duration_seconds{stat=\"last\"} 1.6",
+            "refresh_duration_seconds{stat=\"average\"} ",
+            "fetch_duration_seconds{animal=\"cat\",stat=\"last\"} 0.4",
+            "fetch_duration_seconds{animal=\"dog\",stat=\"average\"} ",
+        ] {
+            assert!(
+                body.lines().any(|l| l.starts_with(prefix)),
+                "{:?} not found in {}",
+                prefix,
+                body
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refresh_interval_check() {
+        let cfg = get_test_config(vec![Animal::Cat, Animal::Dog])
+            .with_shard_refresh_sec(Duration::from_secs(1));
+        let (logs, _guard) = capture_logs();
+
+        // 4 serial fetches take 0.8 sec
+        animals::fake::set_delay(Duration::from_millis(200));
+        let state = init_state(cfg.clone()).unwrap();
+        refresh_shards(&state, 4).await.into_result().unwrap();
+        check_refresh_interval(&state).unwrap();
+        assert_eq!(logs.take(), "");
+
+        // The startup refresh is concurrent and fast enough, but the routine ones won't be
+        animals::fake::set_delay(Duration::from_millis(400));
+        let state = init_state(cfg.clone()).unwrap();
+        refresh_shards(&state, 4).await.into_result().unwrap();
+        assert!(state.refresh_duration().last < Duration::from_secs(1));
+        check_refresh_interval(&state).unwrap();
+        assert!(logs.take().contains("longer than `shard_refresh_sec`"));
+
+        let state = init_state(cfg.with_strict_refresh_interval(true)).unwrap();
+        refresh_shards(&state, 4).await.into_result().unwrap();
+        assert!(matches!(
+            check_refresh_interval(&state),
+            Err(AppError::InvalidConfig(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_animal_weights() {
+        let animals = vec![
+            AnimalSpec {
+                animal: Animal::Cat,
+                weight: 9,
+            },
+            AnimalSpec::from(Animal::Dog),
+        ];
+        let cfg = get_test_config(vec![]).with_animals(animals);
+        let (server, _) = set_up_test_server(cfg).await;
+        let animal_set = HashSet::from(["cat".to_string(), "dog".to_string()]);
+
+        let mut cat_facts = 0;
+        for _ in 0..100 {
+            if get_fact(&server, &animal_set).await.animal == "cat" {
+                cat_facts += 1;
+            }
+        }
+        // 90 cat facts are expected
+        assert!(cat_facts > 70, "Too few cat facts: {}", cat_facts);
+    }
+
+    #[tokio::test]
+    async fn test_fact_weights() {
+        let cfg = get_test_config(vec![Animal::Cat])
+            .with_shard_num(1)
+            .with_shard_size(2);
+        let (server, state) = set_up_test_server(cfg).await;
+        let facts = vec!["rare".to_string(), "common".to_string()];
+        *state.cache[0].shards[0].lock().unwrap() = Shard::new(facts).with_weights(vec![1, 9]);
+
+        let mut common_facts = 0;
+        for _ in 0..100 {
+            let fact: FactResponse = server.get("/fact").await.json();
+            if fact.fact == "common" {
+                common_facts += 1;
+            }
+        }
+        // 90 common facts are expected
+        assert!(common_facts > 70, "Too few common facts: {}", common_facts);
+
+        // Uniform weights aren't stored
+        let shard = Shard::new(vec!["a".to_string(), "b".to_string()]).with_weights(vec![3, 3]);
+        assert_eq!(shard.weights, None);
+    }
+
+    const ADMIN_TOKEN: &str = "secret";
+
+    fn bearer(token: &str) -> HeaderValue {
+        HeaderValue::from_str(&format!("Bearer {}", token)).unwrap()
+    }
+
+    fn timestamps(state: &AppState) -> Vec<DateTime<Utc>> {
+        state
+            .cache
+            .iter()
+            .flat_map(|s| s.shards.iter().map(|s| s.lock().unwrap().timestamp))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_admin_refresh() {
+        let cfg = get_test_config(vec![Animal::Cat, Animal::Dog])
+            .with_admin_token(Some(ADMIN_TOKEN.to_string()));
+        let (server, state) = set_up_test_server(cfg).await;
+        let old_timestamps = timestamps(&state);
+        sleep(Duration::from_secs(1)).await;
+
+        let response = server
+            .post("/admin/refresh")
+            .add_header(AUTHORIZATION, bearer(ADMIN_TOKEN))
+            .await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        let summary: Value = response.json();
+        assert_eq!(
+            summary,
+            serde_json::json!([
+                {"animal": "cat", "success": true},
+                {"animal": "dog", "success": true},
+            ])
+        );
+        for (old, new) in old_timestamps.iter().zip(timestamps(&state)) {
+            assert!(*old < new);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_refresh_unauthorized() {
+        let cfg =
+            get_test_config(vec![Animal::Cat]).with_admin_token(Some(ADMIN_TOKEN.to_string()));
+        let (server, _) = set_up_test_server(cfg).await;
+        for header in [None, Some(bearer("wrong")), Some(bearer(""))] {
+            let mut request = server.post("/admin/refresh");
+            if let Some(header) = header {
+                request = request.add_header(AUTHORIZATION, header);
+            }
+            assert_eq!(request.await.status_code(), StatusCode::UNAUTHORIZED);
+        }
+
+        // Admin endpoints are disabled unless a token is configured
+        let (server, _) = set_up_test_server(get_test_config(vec![Animal::Cat])).await;
+        let response = server
+            .post("/admin/refresh")
+            .add_header(AUTHORIZATION, bearer(""))
+            .await;
+        assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_admin_evict() {
+        let cfg = get_test_config(vec![Animal::Cat, Animal::Dog])
+            .with_admin_token(Some(ADMIN_TOKEN.to_string()));
+        let (server, state) = set_up_test_server(cfg).await;
+        let response = server
+            .post("/admin/evict/cat")
+            .add_header(AUTHORIZATION, bearer(ADMIN_TOKEN))
+            .await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        let summary: Value = response.json();
+        assert_eq!(
+            summary,
+            serde_json::json!({"animal": "cat", "shards": 2, "evicted_facts": 2 * 50})
+        );
+        for shard in &state.cache[0].shards {
+            assert!(shard.lock().unwrap().facts.is_empty());
+        }
+        assert!(!state.cache[1].shards[0].lock().unwrap().facts.is_empty());
+        // Only dog facts are served until the next refresh
+        let animal_set = HashSet::from(["dog".to_string()]);
+        for _ in 0..10 {
+            get_fact(&server, &animal_set).await;
+        }
+        assert_eq!(
+            server.get("/health").await.status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        refresh_shards(&state, 1).await.into_result().unwrap();
+        get_health(&server).await;
+
+        // Unknown animals and the ones which aren't configured
+        let (server, _) = set_up_test_server(
+            get_test_config(vec![Animal::Cat]).with_admin_token(Some(ADMIN_TOKEN.to_string())),
+        )
+        .await;
+        for path in ["/admin/evict/cow", "/admin/evict/dog"] {
+            let response = server
+                .post(path)
+                .add_header(AUTHORIZATION, bearer(ADMIN_TOKEN))
+                .await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+        }
+
+        let response = server
+            .post("/admin/evict/cat")
+            .add_header(AUTHORIZATION, bearer("wrong"))
+            .await;
+        assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_auto_refresh() {
+        let animals = vec![Animal::Cat];
+        let animal_set: HashSet<_> = animals.iter().map(|a| a.to_string()).collect();
+        let cfg = get_test_config(animals).with_shard_refresh_sec(Duration::ZERO);
+        let (server, state) = set_up_test_server(cfg).await;
+        assert!(spawn_refresh_task(&state).is_none());
+
+        let old_timestamps = timestamps(&state);
+        for _ in 0..3 {
+            sleep(Duration::from_millis(500)).await;
+            get_fact(&server, &animal_set).await;
+        }
+        assert_eq!(old_timestamps, timestamps(&state));
+    }
+
+    #[tokio::test]
+    async fn test_once() {
+        let animals = vec![Animal::Cat];
+        let animal_set: HashSet<_> = animals.iter().map(|a| a.to_string()).collect();
+        let cfg = get_test_config(animals)
+            .with_shard_refresh_sec(Duration::from_millis(100))
+            .with_once(true);
+        let (server, state) = set_up_test_server(cfg).await;
+        assert!(spawn_refresh_task(&state).is_none());
+
+        let old_timestamps = timestamps(&state);
+        // Past `shard_staleness_sec`
+        sleep(Duration::from_millis(1100)).await;
+        get_fact(&server, &animal_set).await;
+        assert_eq!(old_timestamps, timestamps(&state));
+        assert_eq!(animals::fake::calls().len(), state.cfg.shard_num);
+        assert_eq!(server.get("/health").await.status_code(), StatusCode::OK);
+        // Staleness can still be checked explicitly
+        let response = server.get("/health").add_query_param("max_age", 1).await;
+        assert_eq!(response.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_order() {
+        // Returns the shards in the order of refreshing
+        async fn refresh_order(order: RefreshOrder) -> Vec<usize> {
+            let cfg = get_test_config(vec![Animal::Cat])
+                .with_shard_num(3)
+                .with_refresh_order(order);
+            let state = init_state(cfg).unwrap();
+            let now = Utc::now();
+            for (shard, age) in state.cache[0].shards.iter().zip([1, 30, 10]) {
+                shard.lock().unwrap().timestamp = now - chrono::Duration::seconds(age);
+            }
+            // Refreshed shards get distinct timestamps
+            animals::fake::set_delay(Duration::from_millis(5));
+            refresh_shards(&state, 1).await.into_result().unwrap();
+            let timestamps = timestamps(&state);
+            let mut shards: Vec<_> = (0..timestamps.len()).collect();
+            shards.sort_by_key(|i| timestamps[*i]);
+            shards
+        }
+
+        assert_eq!(refresh_order(RefreshOrder::Sequential).await, [0, 1, 2]);
+        assert_eq!(refresh_order(RefreshOrder::StalestFirst).await, [1, 2, 0]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_startup_grace() {
+        let cfg = get_test_config(vec![Animal::Cat]).with_startup_grace_sec(5);
+        let (server, state) = set_up_test_server(cfg).await;
+        let stale = Utc::now() - chrono::Duration::seconds(60);
+        state.cache[0].shards[0].lock().unwrap().timestamp = stale;
+
+        assert_eq!(server.get("/health").await.status_code(), StatusCode::OK);
+        // Unpopulated shards are reported even during the grace period
+        let facts = std::mem::take(&mut state.cache[0].shards[1].lock().unwrap().facts);
+        assert_eq!(
+            server.get("/health").await.status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        state.cache[0].shards[1].lock().unwrap().facts = facts;
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(
+            server.get("/health").await.status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test]
+    async fn test_facts() {
+        let cfg = get_test_config(vec![Animal::Cat, Animal::Dog]);
+        let (server, state) = set_up_test_server(cfg).await;
+        let facts: Vec<FactResponse> = server.get("/facts").await.json();
+        assert_eq!(
+            facts.iter().map(|f| f.animal.as_str()).collect::<Vec<_>>(),
+            ["cat", "dog"]
+        );
+        for (fact, shard_set) in facts.iter().zip(state.cache.iter()) {
+            assert!(shard_set
+                .shards
+                .iter()
+                .any(|s| s.lock().unwrap().facts.contains(&fact.fact)));
+        }
+
+        // Cats have no facts
+        for shard in &state.cache[0].shards {
+            shard.lock().unwrap().facts.clear();
+        }
+        let response = server.get("/facts").await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        let facts: Vec<FactResponse> = response.json();
+        assert_eq!(facts.len(), 1);
+        assert_eq!(facts[0].animal, "dog");
+
+        for shard in &state.cache[1].shards {
+            shard.lock().unwrap().facts.clear();
+        }
+        let facts: Vec<FactResponse> = server.get("/facts").await.json();
+        assert!(facts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_shard_store() {
+        let store: Arc<dyn ShardStore> = Arc::new(store::MemoryStore::default());
+        let cfg = get_test_config(vec![Animal::Cat]).with_shard_staleness_sec(10);
+
+        // Readers start before the writer has saved the shards, but have no facts
+        let reader = init_state(cfg.clone())
+            .unwrap()
+            .with_shard_store(store.clone());
+        for _ in 0..cfg.breaker_failure_threshold {
+            refresh_on_startup(&reader).await.unwrap();
+        }
+        match refresh_shards(&reader, 1).await.into_result() {
+            Err(AppError::NoData) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert!(animals::fake::calls().is_empty());
+        let server = TestServer::new(build_router(reader.clone()).into_make_service()).unwrap();
+        let response = server.get("/fact").await;
+        assert_eq!(response.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let writer = init_state(cfg.clone().with_redis_writer(true))
+            .unwrap()
+            .with_shard_store(store.clone());
+        refresh_shards(&writer, 1).await.into_result().unwrap();
+        assert_eq!(animals::fake::calls().len(), cfg.shard_num);
+        for (i, shard) in writer.cache[0].shards.iter().enumerate() {
+            let stored = store.load(Animal::Cat, i).await.unwrap().unwrap();
+            assert_eq!(stored, StoredShard::from(&*shard.lock().unwrap()));
+        }
+
+        // The reader loads the writer's shards instead of requesting the providers;
+        // its breaker hasn't been opened by the failed loads.
+        refresh_shards(&reader, 1).await.into_result().unwrap();
+        assert!(animals::fake::calls().is_empty());
+        for (read, written) in reader.cache[0].shards.iter().zip(&writer.cache[0].shards) {
+            let (read, written) = (read.lock().unwrap(), written.lock().unwrap());
+            assert_eq!(read.facts, written.facts);
+            assert_eq!(
+                read.timestamp.timestamp_millis(),
+                written.timestamp.timestamp_millis()
+            );
+        }
+        assert!(check_app_state(&reader, Some(cfg.shard_staleness_sec)).is_ok());
+        let fact = get_fact(&server, &HashSet::from(["cat".to_string()])).await;
+        assert!(writer.cache[0].shards.iter().any(|s| s
+            .lock()
+            .unwrap()
+            .facts
+            .contains(&fact.fact)));
+
+        // Nothing is connected at startup
+        let redis = cfg.with_redis_url(Some("redis://localhost:6379".to_string()));
+        #[cfg(feature = "redis")]
+        assert!(init_state(redis).is_ok());
+        #[cfg(not(feature = "redis"))]
+        assert!(matches!(init_state(redis), Err(AppError::InvalidConfig(_))));
+    }
+
+    #[tokio::test]
+    async fn test_error_format() {
+        for format in [ErrorFormat::Empty, ErrorFormat::Json] {
+            let cfg = get_test_config(vec![Animal::Cat]).with_error_format(format);
+            let (server, state) = set_up_test_server(cfg).await;
+            for shard in &state.cache[0].shards {
+                shard.lock().unwrap().facts.clear();
+            }
+            let response = server.get("/fact").await;
+            // The status and the headers don't depend on the format
+            assert_eq!(response.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(response.header(RETRY_AFTER), "2");
+            let bad_request = server.get("/health").add_query_param("max_age", 0).await;
+            assert_eq!(bad_request.status_code(), StatusCode::BAD_REQUEST);
+            match format {
+                ErrorFormat::Empty => assert!(response.text().is_empty()),
+                ErrorFormat::Json => {
+                    assert_eq!(response.header("Content-Type"), "application/json");
+                    assert_eq!(
+                        response.json::<Value>(),
+                        serde_json::json!({
+                            "error": {"status": 503, "message": "No facts are available yet"},
+                        })
+                    );
+                    let error = &bad_request.json::<Value>()["error"];
+                    assert_eq!(error["status"], 400);
+                    assert!(error["message"].as_str().unwrap().contains("query string"));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let cfg = get_test_config(vec![Animal::Cat, Animal::Dog])
+            .with_shard_num(3)
+            .with_shard_size(7);
+        let (server, _) = set_up_test_server(cfg).await;
+        let response = server.get("/stats").await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.header("Cache-Control"), "no-cache");
+        let stats: Vec<AnimalStats> = response.json();
+        assert_eq!(
+            stats.iter().map(|s| s.animal.as_str()).collect::<Vec<_>>(),
+            ["cat", "dog"]
+        );
+        for animal in stats {
+            assert_eq!(animal.facts, 3 * 7);
+            assert!((0..=1).contains(&animal.oldest_shard_age_sec));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_openapi() {
+        let (server, _) = set_up_test_server(get_test_config(vec![Animal::Cat])).await;
+        let response = server.get("/openapi.json").await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        let spec: Value = serde_json::from_str(&response.text()).unwrap();
+        assert!(spec["paths"]["/fact"]["get"].is_object());
+        assert!(spec["paths"]["/health"]["get"].is_object());
+        assert!(spec["paths"]["/stats"]["get"].is_object());
+
+        // The documented schema should match the actual response
+        let schema = &spec["components"]["schemas"]["Fact"]["properties"];
+        let fact: Value = server.get("/fact").await.json();
+        for (key, value) in fact.as_object().unwrap() {
+            assert!(value.is_string());
+            assert_eq!(schema[key]["type"], "string", "{:?} isn't documented", key);
+        }
+        assert!(schema["animal"]["enum"]
+            .as_array()
+            .unwrap()
+            .contains(&fact["animal"]));
+    }
+
+    #[tokio::test]
+    async fn test_recent_facts() {
+        // Counts the facts equal to the previous ones
+        async fn count_repeats(cfg: ServerConfig) -> usize {
+            let (server, _) = set_up_test_server(cfg).await;
+            let animal_set = HashSet::from(["cat".to_string()]);
+            let mut repeats = 0;
+            let mut previous = None;
+            for _ in 0..300 {
+                let fact = get_fact(&server, &animal_set).await.fact;
+                if previous.as_ref() == Some(&fact) {
+                    repeats += 1;
+                }
+                previous = Some(fact);
+            }
+            repeats
+        }
+
+        let cfg = get_test_config(vec![Animal::Cat])
+            .with_shard_num(1)
+            .with_shard_size(10);
+        // About 10% of the facts are repeated without the memory
+        let unbuffered = count_repeats(cfg.clone()).await;
+        let buffered = count_repeats(cfg.with_recent_facts_memory(5)).await;
+        assert!(
+            buffered * 3 < unbuffered,
+            "{} repeats with the memory, {} without it",
+            buffered,
+            unbuffered
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fact_with_id() {
+        let (server, _) = set_up_test_server(get_test_config(vec![Animal::Cat])).await;
+        for _ in 0..REQUEST_NUM {
+            let request = server.get("/fact").add_query_param("with_id", true);
+            let fact: FactResponse = request.await.json();
+            assert_eq!(fact.id, Some(FactId::of(&fact.fact).to_string()));
+        }
+        // The id is opt-in
+        let fact = check_fact(
+            server.get("/fact").add_query_param("with_id", false).await,
+            &HashSet::from(["cat".to_string()]),
+        );
+        assert_eq!(fact.id, None);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let cfg = get_test_config(vec![Animal::Cat])
+            .with_shard_num(1)
+            .with_breaker_failure_threshold(2)
+            .with_breaker_cooldown_sec(1);
+        let (server, state) = set_up_test_server(cfg).await;
+        let breaker_state = || state.cache[0].breaker().state();
+        let failure = || Err(AppError::UnexpectedStatusCode(StatusCode::BAD_GATEWAY));
+        animals::fake::script(Animal::Cat, vec![failure(), failure()]);
+        animals::fake::calls();
+
+        assert!(refresh_shards(&state, 1).await.into_result().is_err());
+        assert_eq!(breaker_state(), BreakerState::Closed);
+        assert!(refresh_shards(&state, 1).await.into_result().is_err());
+        assert_eq!(breaker_state(), BreakerState::Open);
+        assert_eq!(animals::fake::calls().len(), 2);
+
+        // The provider isn't requested, but the old facts are still served
+        let result = refresh_shards(&state, 1).await.into_result();
+        assert!(matches!(result, Err(AppError::CircuitOpen)));
+        assert!(animals::fake::calls().is_empty());
+        get_fact(&server, &HashSet::from(["cat".to_string()])).await;
+        let metrics = server.get("/metrics").await.text();
+        assert!(metrics.contains("circuit_breaker_state{animal=\"cat\"} 1"));
+
+        sleep(Duration::from_secs(1)).await;
+        assert_eq!(breaker_state(), BreakerState::HalfOpen);
+        refresh_shards(&state, 1).await.into_result().unwrap();
+        assert_eq!(breaker_state(), BreakerState::Closed);
+        assert_eq!(animals::fake::calls().len(), 1);
+    }
+
+    // Imitates a translation provider, which prepends the language to the text;
+    // it fails for the "xx" language.
+    async fn spawn_mock_translator() -> (String, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_clone = calls.clone();
+        let translate = move |Query(params): Query<HashMap<String, String>>| async move {
+            calls_clone.fetch_add(1, Ordering::SeqCst);
+            if params["lang"] == "xx" {
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            let text = format!("[{}] {}", params["lang"], params["text"]);
+            Ok(Json(serde_json::json!({ "text": text })))
+        };
+        let url = spawn_mock_server(Router::new().route("/translate", get(translate))).await;
+        (format!("{}/translate", url), calls)
+    }
+
+    async fn set_up_translating_server() -> (TestServer, Arc<AtomicUsize>) {
+        let (url, calls) = spawn_mock_translator().await;
+        let cfg = get_test_config(vec![Animal::Cat])
+            .with_translation_url(Some(url))
+            .with_translation_langs(vec!["de".to_string(), "xx".to_string()]);
+        (set_up_test_server(cfg).await.0, calls)
+    }
+
+    #[tokio::test]
+    async fn test_translation() {
+        let (server, calls) = set_up_translating_server().await;
+        let fact: FactResponse = server
+            .get("/fact")
+            .add_query_param("lang", "de")
+            .add_query_param("with_id", true)
+            .await
+            .json();
+        assert_eq!(fact.lang.as_deref(), Some("de"));
+        let original = fact.fact.strip_prefix("[de] ").unwrap();
+        // The id concerns the original fact
+        assert_eq!(fact.id, Some(FactId::of(original).to_string()));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Neither English nor no language require translation
+        for request in [
+            server.get("/fact").add_query_param("lang", "en"),
+            server.get("/fact").add_query_param("lang", "EN"),
+        ] {
+            let fact: FactResponse = request.await.json();
+            assert_eq!(fact.lang.as_deref(), Some("en"));
+        }
+        let fact = check_fact(
+            server.get("/fact").await,
+            &HashSet::from(["cat".to_string()]),
+        );
+        assert_eq!(fact.lang, None);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_translation_fallback() {
+        let (server, calls) = set_up_translating_server().await;
+        // The provider fails
+        let fact: FactResponse = server
+            .get("/fact")
+            .add_query_param("lang", "xx")
+            .await
+            .json();
+        assert_eq!(fact.lang.as_deref(), Some("en"));
+        assert!(fact.fact.starts_with("cat fact"));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // The language isn't supported
+        let fact: FactResponse = server
+            .get("/fact")
+            .add_query_param("lang", "fr")
+            .await
+            .json();
+        assert_eq!(fact.lang.as_deref(), Some("en"));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit() {
+        use std::future::IntoFuture;
+
+        // The translations hang until the gate is opened
+        let gate = Arc::new(tokio::sync::Semaphore::new(0));
+        let arrivals = Arc::new(AtomicUsize::new(0));
+        let (gate_clone, arrivals_clone) = (gate.clone(), arrivals.clone());
+        let translate = move || async move {
+            arrivals_clone.fetch_add(1, Ordering::SeqCst);
+            let _permit = gate_clone.acquire().await.unwrap();
+            Json(serde_json::json!({ "text": "translated" }))
+        };
+        let url = spawn_mock_server(Router::new().route("/", get(translate))).await;
+        let cfg = get_test_config(vec![Animal::Cat])
+            .with_max_concurrent_requests(2)
+            .with_translation_url(Some(url))
+            .with_translation_langs(vec!["de".to_string()]);
+        let (server, _) = set_up_test_server(cfg).await;
+
+        let slow_request = || {
+            server
+                .get("/fact")
+                .add_query_param("lang", "de")
+                .into_future()
+        };
+        let saturate = async { tokio::join!(slow_request(), slow_request()) };
+        let probe = async {
+            while arrivals.load(Ordering::SeqCst) < 2 {
+                sleep(Duration::from_millis(10)).await;
+            }
+            let excess = server.get("/fact").await;
+            let health = server.get("/health").await;
+            gate.add_permits(2);
+            (excess, health)
+        };
+        let ((first, second), (excess, health)) = tokio::join!(saturate, probe);
+
+        assert_eq!(first.status_code(), StatusCode::OK);
+        assert_eq!(second.status_code(), StatusCode::OK);
+        assert_eq!(excess.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(health.status_code(), StatusCode::OK);
+        // The permits are released
+        assert_eq!(server.get("/fact").await.status_code(), StatusCode::OK);
+    }
+
+    // Reads `n` fact events from an SSE response
+    async fn read_fact_events(response: &mut reqwest::Response, n: usize) -> Vec<FactResponse> {
+        let mut buffer = String::new();
+        let mut facts = vec![];
+        while facts.len() < n {
+            let chunk = response
+                .chunk()
+                .await
+                .unwrap()
+                .expect("The stream has ended");
+            buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+            while let Some((event, rest)) = buffer.split_once("\n\n") {
+                // The space after the colon is optional
+                let fields: HashMap<_, _> = event
+                    .lines()
+                    .filter_map(|l| l.split_once(':'))
+                    .map(|(name, value)| (name, value.strip_prefix(' ').unwrap_or(value)))
+                    .collect();
+                if fields.get("event") == Some(&"fact") {
+                    facts.push(serde_json::from_str(fields["data"]).unwrap());
+                }
+                buffer = rest.to_string();
+            }
+        }
+        facts
+    }
+
+    #[tokio::test]
+    async fn test_fact_stream() {
+        let cfg = get_test_config(vec![Animal::Cat]).with_max_streams(1);
+        let state = init_state(cfg).unwrap();
+        refresh_shards(&state, 1).await.into_result().unwrap();
+        let url = spawn_mock_server(build_router(state.clone())).await;
+        let client = reqwest::Client::new();
+
+        let mut response = client
+            .get(format!("{}/fact/stream", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        for fact in read_fact_events(&mut response, 2).await {
+            assert_eq!(fact.animal, "cat");
+            assert!(fact.fact.starts_with("cat fact"));
+        }
+
+        // The number of streams is limited
+        let rejected = client
+            .get(format!("{}/fact/stream", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(rejected.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // The stream is dropped once the server notices the disconnection
+        drop(response);
+        let start = Instant::now();
+        while state.streams.available_permits() == 0 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "The stream leaked"
+            );
+            sleep(Duration::from_millis(50)).await;
+        }
+        let mut response = client
+            .get(format!("{}/fact/stream", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(read_fact_events(&mut response, 1).await.len(), 1);
+    }
+
+    // Replaces the facts of each shard with a single one containing the shard index,
+    // so that one can see which shard a fact comes from.
+    fn label_shards(state: &AppState) {
+        for (i, shard) in state.cache[0].shards.iter().enumerate() {
+            *shard.lock().unwrap() = Shard::new(vec![i.to_string()]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_seeded_rng() {
+        async fn facts(seed: u64) -> Vec<String> {
+            let cfg = get_test_config(vec![Animal::Cat, Animal::Dog]).with_shard_num(2);
+            let state = init_state(cfg)
+                .unwrap()
+                .with_rng_source(rng::SeededRngSource::new(seed));
+            for (animal, shard_set) in ["cat", "dog"].iter().zip(state.cache.iter()) {
+                for (i, shard) in shard_set.shards.iter().enumerate() {
+                    let facts = (0..3).map(|j| format!("{} {}.{}", animal, i, j)).collect();
+                    *shard.lock().unwrap() = Shard::new(facts);
+                }
+            }
+            let server = TestServer::new(build_router(state).into_make_service()).unwrap();
+            let mut facts = vec![];
+            for _ in 0..8 {
+                let fact: FactResponse = server.get("/fact").await.json();
+                facts.push(fact.fact);
+            }
+            facts
+        }
+
+        // The sequence only depends on the seed (and the version of `rand`)
+        let expected = [
+            "cat 1.0", "dog 1.1", "dog 0.1", "cat 1.0", "dog 1.0", "cat 0.2", "dog 1.1", "dog 1.0",
+        ];
+        assert_eq!(facts(42).await, expected);
+        assert_eq!(facts(42).await, expected);
+        assert_ne!(facts(43).await, expected);
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_shard_selection() {
+        let cfg = get_test_config(vec![Animal::Cat])
+            .with_shard_num(3)
+            .with_shard_selection(ShardSelection::RoundRobin);
+        let (server, state) = set_up_test_server(cfg).await;
+        label_shards(&state);
+
+        let animal_set = HashSet::from(["cat".to_string()]);
+        let mut shards = vec![];
+        for _ in 0..7 {
+            shards.push(get_fact(&server, &animal_set).await.fact);
+        }
+        assert_eq!(shards, ["0", "1", "2", "0", "1", "2", "0"]);
+    }
+
+    #[tokio::test]
+    async fn test_weighted_round_robin_shard_selection() {
+        let cfg = get_test_config(vec![Animal::Cat])
+            .with_shard_staleness_sec(10)
+            .with_shard_selection(ShardSelection::WeightedRoundRobin);
+        let (server, state) = set_up_test_server(cfg).await;
+        label_shards(&state);
+        // Half-stale, so the weights are 1 and 0.5
+        state.cache[0].shards[1].lock().unwrap().timestamp =
+            Utc::now() - chrono::Duration::seconds(5);
+
+        let animal_set = HashSet::from(["cat".to_string()]);
+        let mut shards = vec![];
+        for _ in 0..6 {
+            shards.push(get_fact(&server, &animal_set).await.fact);
+        }
+        assert_eq!(shards, ["0", "1", "0", "0", "1", "0"]);
+    }
+
+    #[tokio::test]
+    async fn test_random_shard_selection() {
+        let cfg = get_test_config(vec![Animal::Cat]).with_shard_num(3);
+        let (server, state) = set_up_test_server(cfg).await;
+        label_shards(&state);
+
+        let animal_set = HashSet::from(["cat".to_string()]);
+        let mut shards = HashSet::new();
+        for _ in 0..50 {
+            shards.insert(get_fact(&server, &animal_set).await.fact);
+        }
+        assert_eq!(shards, HashSet::from(["0", "1", "2"].map(String::from)));
+    }
+
+    #[tokio::test]
+    async fn test_no_data() {
+        // The shards haven't been refreshed yet
+        let state = init_state(get_test_config(vec![Animal::Cat])).unwrap();
+        let server = TestServer::new(build_router(state).into_make_service()).unwrap();
+        let response = server.get("/fact").await;
+        assert_eq!(response.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.header(RETRY_AFTER), "2");
+
+        // No refresh is scheduled, so there's no point in retrying
+        let cfg = get_test_config(vec![Animal::Cat]).with_shard_refresh_sec(Duration::ZERO);
+        let server =
+            TestServer::new(build_router(init_state(cfg).unwrap()).into_make_service()).unwrap();
+        let response = server.get("/fact").await;
+        assert_eq!(response.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.maybe_header(RETRY_AFTER), None);
+    }
+
+    #[tokio::test]
+    async fn test_empty_shards() {
+        let cfg = get_test_config(vec![Animal::Cat, Animal::Dog]).with_shard_num(3);
+        let (server, state) = set_up_test_server(cfg).await;
+        let animal_set = HashSet::from(["cat".to_string(), "dog".to_string()]);
+        // E.g. a partial refresh has failed
+        for i in [0, 2] {
+            state.cache[0].shards[i].lock().unwrap().facts.clear();
+        }
+        for _ in 0..REQUEST_NUM {
+            get_fact(&server, &animal_set).await;
+        }
+
+        // Only another animal has facts
+        state.cache[0].shards[1].lock().unwrap().facts.clear();
+        for _ in 0..REQUEST_NUM {
+            let fact = get_fact(&server, &animal_set).await;
+            assert_eq!(fact.animal, "dog");
+        }
+
+        for shard in &state.cache[1].shards {
+            shard.lock().unwrap().facts.clear();
+        }
+        let response = server.get("/fact").await;
+        assert_eq!(response.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_freshness_weighted_shard_selection() {
+        let cfg = get_test_config(vec![Animal::Cat])
+            .with_shard_num(3)
+            .with_shard_staleness_sec(10)
+            .with_freshness_weighted(true);
+        let (server, state) = set_up_test_server(cfg).await;
+        label_shards(&state);
+        // The shards are fresh, half-stale and stale respectively
+        for (i, age) in [0, 5, 20].iter().enumerate() {
+            state.cache[0].shards[i].lock().unwrap().timestamp =
+                Utc::now() - chrono::Duration::seconds(*age);
+        }
+
+        let animal_set = HashSet::from(["cat".to_string()]);
+        let mut counts = [0; 3];
+        for _ in 0..300 {
+            let shard: usize = get_fact(&server, &animal_set).await.fact.parse().unwrap();
+            counts[shard] += 1;
+        }
+        // The expected counts are 194, 97 and 10
+        assert!(counts[0] > counts[1], "{:?}", counts);
+        assert!(counts[1] > counts[2], "{:?}", counts);
+        assert!(counts[2] > 0, "{:?}", counts);
+    }
+
+    // The time is paused, so the fake delays are measured exactly.
+    #[tokio::test(start_paused = true)]
+    async fn test_startup_concurrency() {
+        animals::fake::set_delay(Duration::from_millis(200));
+        let cfg = get_test_config(vec![Animal::Cat, Animal::Dog]).with_shard_staleness_sec(10);
+        let state = init_state(cfg).unwrap();
+        let mut durations = vec![];
+        for concurrency in [1, 4] {
+            let start = tokio::time::Instant::now();
+            refresh_shards(&state, concurrency)
+                .await
+                .into_result()
+                .unwrap();
+            durations.push(start.elapsed());
+            assert!(check_app_state(&state, Some(state.cfg.shard_staleness_sec)).is_ok());
+        }
+        // 4 shards are refreshed: one by one and all at once respectively
+        assert!(durations[0] >= Duration::from_millis(800));
+        assert!(durations[0] < Duration::from_millis(840));
+        assert!(durations[1] >= Duration::from_millis(200));
+        assert!(durations[1] < Duration::from_millis(210));
+    }
+
+    #[tokio::test]
+    async fn test_config_endpoint() {
+        let cfg = get_test_config(vec![Animal::Cat, Animal::Dog])
+            .with_admin_token(Some(ADMIN_TOKEN.to_string()));
+        let (server, _) = set_up_test_server(cfg).await;
+
+        let response = server
+            .get("/config")
+            .add_header(AUTHORIZATION, bearer(ADMIN_TOKEN))
+            .await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        let body = response.text();
+        assert!(!body.contains(ADMIN_TOKEN));
+        let cfg: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(cfg["admin_token"], "REDACTED");
+        assert_eq!(cfg["shard_num"], 2);
+        assert_eq!(cfg["verbosity"], "TRACE");
+        assert_eq!(cfg["shard_selection"], "random");
+
+        let response = server.get("/config").await;
+        assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+        let response = server
+            .get("/config")
+            .add_header(AUTHORIZATION, bearer("wrong"))
+            .await;
+        assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+    }
+}
+
+// Run with `cargo test --no-default-features --features <animal>`.
+#[cfg(all(test, not(all(feature = "dog", feature = "cat"))))]
+mod single_provider_test {
+    use crate::*;
+
+    use axum_test::TestServer;
+    use clap::{Parser, ValueEnum};
+
+    #[tokio::test]
+    async fn test_single_provider() {
+        let [animal] = Animal::value_variants() else {
+            panic!("A single animal is expected");
+        };
+        let cfg = ServerConfig::default().with_shard_staleness_sec(10);
+        let state = init_state(cfg).unwrap();
+        refresh_shards(&state, 1).await.into_result().unwrap();
+        assert!(check_app_state(&state, Some(state.cfg.shard_staleness_sec)).is_ok());
+
+        let server = TestServer::new(build_router(state).into_make_service()).unwrap();
+        let fact: FactResponse = server.get("/fact").await.json();
+        assert_eq!(fact.animal, animal.to_string());
+        assert_eq!(server.get("/health").await.status_code(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_disabled_provider() {
+        let [animal] = Animal::value_variants() else {
+            panic!("A single animal is expected");
+        };
+        let disabled = if animal.to_string() == "dog" {
+            "cat"
+        } else {
+            "dog"
+        };
+        let Err(e) = ServerConfig::try_parse_from(["shuttle-test", "--animals", disabled]) else {
+            panic!("Disabled animal accepted");
+        };
+        assert!(e.to_string().contains("cargo feature"), "{}", e);
+    }
+}
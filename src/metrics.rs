@@ -0,0 +1,215 @@
+// This module renders the metrics in the Prometheus text format.
+// They are few, so a metrics library doesn't seem necessary yet.
+
+use axum::extract::State;
+use axum::http::HeaderMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::breaker::BreakerState;
+use crate::errors::AppError;
+use crate::AppState;
+
+const LENGTH_RANGES: [&str; 4] = ["0-50", "51-100", "101-200", "200+"];
+
+// Distribution of fact lengths (in chars); it helps to choose length limits for facts.
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct LengthHistogram(pub [usize; LENGTH_RANGES.len()]);
+
+impl LengthHistogram {
+    pub fn from_facts(facts: &[String]) -> Self {
+        let mut histogram = Self::default();
+        for fact in facts {
+            let bucket = match fact.chars().count() {
+                0..=50 => 0,
+                51..=100 => 1,
+                101..=200 => 2,
+                _ => 3,
+            };
+            histogram.0[bucket] += 1;
+        }
+        histogram
+    }
+
+    pub fn add(&mut self, other: &Self) {
+        for (count, other_count) in self.0.iter_mut().zip(other.0) {
+            *count += other_count;
+        }
+    }
+}
+
+// Weight of the latest sample in the rolling average
+const SMOOTHING: f64 = 0.2;
+
+// Durations of a recurring operation (e.g. refreshes); the average is exponentially weighted,
+// so that it follows the recent trend.
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct DurationStats {
+    pub last: Duration,
+    pub average: Duration,
+    pub count: u64,
+}
+
+impl DurationStats {
+    pub fn record(&mut self, duration: Duration) {
+        self.average = if self.count == 0 {
+            duration
+        } else {
+            self.average.mul_f64(1.0 - SMOOTHING) + duration.mul_f64(SMOOTHING)
+        };
+        self.last = duration;
+        self.count += 1;
+    }
+}
+
+fn write_duration_stats(body: &mut String, name: &str, labels: &str, stats: &DurationStats) {
+    for (stat, duration) in [("last", stats.last), ("average", stats.average)] {
+        writeln!(
+            body,
+            "{}{{{}stat=\"{}\"}} {}",
+            name,
+            labels,
+            stat,
+            duration.as_secs_f64()
+        )
+        .unwrap();
+    }
+}
+
+pub(crate) async fn metrics(
+    State(state): State<AppState>,
+) -> Result<(HeaderMap, String), AppError> {
+    let mut headers = HeaderMap::new();
+    headers.insert("Cache-Control", "no-cache".parse().unwrap());
+    headers.insert("Content-Type", "text/plain; version=0.0.4".parse().unwrap());
+
+    let mut body = String::new();
+    writeln!(
+        body,
+        "# HELP fact_length Number of cached facts by length (chars)"
+    )
+    .unwrap();
+    writeln!(body, "# TYPE fact_length gauge").unwrap();
+    for shard_set in state.cache.as_ref() {
+        let mut histogram = LengthHistogram::default();
+        for shard in &shard_set.shards {
+            histogram.add(&shard.lock()?.length_histogram);
+        }
+        for (range, count) in LENGTH_RANGES.iter().zip(histogram.0) {
+            writeln!(
+                body,
+                "fact_length{{animal=\"{}\",range=\"{}\"}} {}",
+                shard_set.animal, range, count
+            )
+            .unwrap();
+        }
+    }
+
+    writeln!(
+        body,
+        "# HELP circuit_breaker_state State of the fact provider's circuit breaker \
+        (0 - closed, 1 - open, 2 - half-open)"
+    )
+    .unwrap();
+    writeln!(body, "# TYPE circuit_breaker_state gauge").unwrap();
+    for shard_set in state.cache.as_ref() {
+        let breaker_state = match shard_set.breaker().state() {
+            BreakerState::Closed => 0,
+            BreakerState::Open => 1,
+            BreakerState::HalfOpen => 2,
+        };
+        writeln!(
+            body,
+            "circuit_breaker_state{{animal=\"{}\"}} {}",
+            shard_set.animal, breaker_state
+        )
+        .unwrap();
+    }
+
+    writeln!(
+        body,
+        "# HELP refresh_duration_seconds Duration of refreshing all the shards"
+    )
+    .unwrap();
+    writeln!(body, "# TYPE refresh_duration_seconds gauge").unwrap();
+    write_duration_stats(
+        &mut body,
+        "refresh_duration_seconds",
+        "",
+        &state.refresh_duration(),
+    );
+
+    writeln!(
+        body,
+        "# HELP fetch_duration_seconds Duration of fetching a shard of facts"
+    )
+    .unwrap();
+    writeln!(body, "# TYPE fetch_duration_seconds gauge").unwrap();
+    for shard_set in state.cache.as_ref() {
+        write_duration_stats(
+            &mut body,
+            "fetch_duration_seconds",
+            &format!("animal=\"{}\",", shard_set.animal),
+            &shard_set.fetch_duration(),
+        );
+    }
+
+    writeln!(
+        body,
+        "# HELP provider_latency_seconds Response time of a provider mirror; failures count as timeouts"
+    )
+    .unwrap();
+    writeln!(body, "# TYPE provider_latency_seconds gauge").unwrap();
+    for shard_set in state.cache.as_ref() {
+        for (n, latencies) in shard_set.latencies.iter().enumerate() {
+            for (mirror, stats) in latencies.snapshot().iter().enumerate() {
+                write_duration_stats(
+                    &mut body,
+                    "provider_latency_seconds",
+                    &format!(
+                        "animal=\"{}\",provider=\"{}\",mirror=\"{}\",",
+                        shard_set.animal, n, mirror
+                    ),
+                    stats,
+                );
+            }
+        }
+    }
+    Ok((headers, body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_length_histogram() {
+        let facts: Vec<_> = [0, 1, 50, 51, 100, 101, 150, 200, 201, 1000]
+            .iter()
+            .map(|len| "x".repeat(*len))
+            .collect();
+        assert_eq!(LengthHistogram::from_facts(&facts).0, [3, 2, 3, 2]);
+        // Lengths are measured in chars, not bytes
+        assert_eq!(
+            LengthHistogram::from_facts(&["ё".repeat(50)]).0,
+            [1, 0, 0, 0]
+        );
+
+        let mut histogram = LengthHistogram::from_facts(&facts);
+        histogram.add(&LengthHistogram::from_facts(&facts[..2]));
+        assert_eq!(histogram.0, [5, 2, 3, 2]);
+    }
+
+    #[test]
+    fn test_duration_stats() {
+        let mut stats = DurationStats::default();
+        stats.record(Duration::from_secs(10));
+        assert_eq!(stats.last, Duration::from_secs(10));
+        assert_eq!(stats.average, Duration::from_secs(10));
+
+        stats.record(Duration::from_secs(20));
+        assert_eq!(stats.last, Duration::from_secs(20));
+        assert_eq!(stats.average, Duration::from_secs(12));
+        assert_eq!(stats.count, 2);
+    }
+}
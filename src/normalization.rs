@@ -0,0 +1,146 @@
+// Optional cleanup of the facts received from providers. HTML is handled by hand,
+// as only tags and a few entities are expected, so a full parser seems excessive.
+
+use crate::config::ServerConfig;
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct Normalization {
+    pub strip_html: bool,
+    pub normalize_whitespace: bool,
+    pub trim: bool,
+    pub capitalize: bool,
+}
+
+impl From<&ServerConfig> for Normalization {
+    fn from(cfg: &ServerConfig) -> Self {
+        Self {
+            strip_html: cfg.strip_html,
+            normalize_whitespace: cfg.normalize_whitespace,
+            trim: cfg.trim_facts,
+            capitalize: cfg.capitalize_facts,
+        }
+    }
+}
+
+impl Normalization {
+    pub fn apply(&self, fact: String) -> String {
+        let mut fact = fact;
+        if self.strip_html {
+            fact = strip_html(&fact);
+        }
+        if self.normalize_whitespace {
+            fact = fact.split_whitespace().collect::<Vec<_>>().join(" ");
+        }
+        if self.trim {
+            fact = fact.trim().to_string();
+        }
+        if self.capitalize {
+            fact = capitalize(&fact);
+        }
+        fact
+    }
+}
+
+// Tags are removed before entities are decoded, so that `&lt;b&gt;` remains visible as `<b>`.
+fn strip_html(fact: &str) -> String {
+    let mut text = String::with_capacity(fact.len());
+    let mut in_tag = false;
+    for c in fact.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => (),
+        }
+    }
+    decode_entities(&text)
+}
+
+// Unknown entities are kept as is.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 10)
+            .and_then(|end| decode_entity(&rest[1..end + 1]).map(|c| (c, end + 2)));
+        match entity {
+            Some((c, len)) => {
+                decoded.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+fn decode_entity(name: &str) -> Option<char> {
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        _ => {
+            let code = match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => name.strip_prefix('#')?.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+fn capitalize(fact: &str) -> String {
+    let mut chars = fact.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalization() {
+        let all = Normalization {
+            strip_html: true,
+            normalize_whitespace: true,
+            trim: true,
+            capitalize: true,
+        };
+        let cases = [
+            ("<p>cats <b>purr</b></p>", "Cats purr"),
+            ("  dogs\n\tbark  ", "Dogs bark"),
+            ("fish &amp; chips &lt;b&gt;", "Fish & chips <b>"),
+            (
+                "&quot;meow&quot;&#33; &#x2764;&nbsp;&unknown; &",
+                "\"meow\"! ❤ &unknown; &",
+            ),
+            ("<br/> ", ""),
+            ("ёж", "Ёж"),
+        ];
+        for (fact, expected) in cases {
+            assert_eq!(all.apply(fact.to_string()), expected, "{:?}", fact);
+        }
+
+        let fact = " <i>cats</i>  &amp; dogs ";
+        assert_eq!(Normalization::default().apply(fact.to_string()), fact);
+        let trim_only = Normalization {
+            trim: true,
+            ..Default::default()
+        };
+        assert_eq!(trim_only.apply(fact.to_string()), "<i>cats</i>  &amp; dogs");
+    }
+}
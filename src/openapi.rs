@@ -0,0 +1,182 @@
+// OpenAPI description of the public endpoints. It's written by hand as the API is tiny;
+// tests check that it stays in line with the actual responses.
+
+use axum::Json;
+use serde_json::{json, Value};
+
+pub async fn openapi() -> Json<Value> {
+    Json(json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/fact": {
+                "get": {
+                    "summary": "Returns a random fact about an animal",
+                    "parameters": [
+                        {
+                            "name": "exclude",
+                            "in": "query",
+                            "required": false,
+                            "description": "Comma-separated ids of the facts to avoid if possible",
+                            "schema": {"type": "string"},
+                        },
+                        {
+                            "name": "with_id",
+                            "in": "query",
+                            "required": false,
+                            "description": "Whether to include the fact id into the response",
+                            "schema": {"type": "boolean", "default": false},
+                        },
+                        {
+                            "name": "lang",
+                            "in": "query",
+                            "required": false,
+                            "description": "Language to translate the fact into; \
+                                if it's unsupported or translation fails, the English fact is returned",
+                            "schema": {"type": "string"},
+                        },
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "A fact",
+                            "headers": {
+                                "X-Content-Digest": {
+                                    "description": "64-bit FNV-1a hash of the body (in hex)",
+                                    "schema": {"type": "string"},
+                                },
+                            },
+                            "content": {
+                                "application/json": {
+                                    "schema": {"$ref": "#/components/schemas/Fact"},
+                                },
+                            },
+                        },
+                        "503": {
+                            "description": "No facts are available yet, or the server is overloaded",
+                            "headers": {
+                                "Retry-After": {
+                                    "description": "Seconds till the next refresh of facts (if there are no facts)",
+                                    "schema": {"type": "integer"},
+                                },
+                            },
+                        },
+                    },
+                },
+            },
+            "/fact/stream": {
+                "get": {
+                    "summary": "Streams random animal facts as Server-Sent Events",
+                    "responses": {
+                        "200": {
+                            "description": "`fact` events with the data of the `Fact` schema",
+                            "content": {"text/event-stream": {"schema": {"type": "string"}}},
+                        },
+                        "503": {"description": "Too many streams are open"},
+                    },
+                },
+            },
+            "/facts": {
+                "get": {
+                    "summary": "Returns a random fact about each animal; the animals without facts are omitted",
+                    "responses": {
+                        "200": {
+                            "description": "Facts in the order of the configured animals",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "array",
+                                        "items": {"$ref": "#/components/schemas/Fact"},
+                                    },
+                                },
+                            },
+                        },
+                    },
+                },
+            },
+            "/health": {
+                "get": {
+                    "summary": "Checks if the server is OK",
+                    "parameters": [
+                        {
+                            "name": "max_age",
+                            "in": "query",
+                            "required": false,
+                            "description": "Maximal age of a shard (sec), overrides the configured staleness threshold",
+                            "schema": {"type": "integer", "minimum": 1},
+                        },
+                    ],
+                    "responses": {
+                        "200": {"description": "The server is healthy"},
+                        "400": {"description": "Invalid `max_age`"},
+                        "500": {"description": "The server is unhealthy (e.g. its facts are stale)"},
+                    },
+                },
+            },
+            "/stats": {
+                "get": {
+                    "summary": "Returns the number of cached facts per animal",
+                    "responses": {
+                        "200": {
+                            "description": "Stats of each configured animal",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "array",
+                                        "items": {"$ref": "#/components/schemas/AnimalStats"},
+                                    },
+                                },
+                            },
+                        },
+                    },
+                },
+            },
+            "/metrics": {
+                "get": {
+                    "summary": "Returns metrics in the Prometheus text format",
+                    "responses": {
+                        "200": {
+                            "description": "Metrics",
+                            "content": {"text/plain": {"schema": {"type": "string"}}},
+                        },
+                    },
+                },
+            },
+        },
+        "components": {
+            "schemas": {
+                "Fact": {
+                    "type": "object",
+                    "required": ["animal", "fact"],
+                    "properties": {
+                        "animal": {"type": "string", "enum": ["dog", "cat"]},
+                        "fact": {"type": "string"},
+                        "id": {
+                            "type": "string",
+                            "description": "64-bit FNV-1a hash of the fact text (in hex), \
+                                present if requested",
+                        },
+                        "lang": {
+                            "type": "string",
+                            "description": "Language of the fact, present if a language was requested",
+                        },
+                    },
+                },
+                "AnimalStats": {
+                    "type": "object",
+                    "required": ["animal", "facts", "oldest_shard_age_sec"],
+                    "properties": {
+                        "animal": {"type": "string", "enum": ["dog", "cat"]},
+                        "facts": {
+                            "type": "integer",
+                            "description": "Number of cached facts, summed across the shards",
+                        },
+                        "oldest_shard_age_sec": {"type": "integer"},
+                    },
+                },
+            },
+        },
+    }))
+}
@@ -0,0 +1,249 @@
+// Fact providers of each animal. A refresh tries them in order, so secondary
+// providers are only requested if the primary one fails.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::animals::Animal;
+use crate::errors::AppError;
+use crate::metrics::DurationStats;
+
+const SHARD_SIZE_PLACEHOLDER: &str = "{shard_size}";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Provider {
+    // `{shard_size}` is replaced with the number of facts requested
+    pub url: String,
+    // Alternative URLs of the same provider (e.g. in other regions); the fastest one is preferred
+    pub mirrors: Vec<String>,
+    // The animal whose built-in provider returns the same format,
+    // i.e. which validator checks the responses
+    pub format: Animal,
+}
+
+impl Provider {
+    pub fn url(&self, shard_size: usize) -> String {
+        self.mirror_url(0, shard_size)
+    }
+
+    // The main URL is mirror #0
+    pub fn mirror_url(&self, mirror: usize, shard_size: usize) -> String {
+        let url = match mirror {
+            0 => &self.url,
+            i => &self.mirrors[i - 1],
+        };
+        url.replace(SHARD_SIZE_PLACEHOLDER, &shard_size.to_string())
+    }
+
+    pub fn mirror_num(&self) -> usize {
+        1 + self.mirrors.len()
+    }
+
+    pub fn builtin(animal: Animal) -> Self {
+        let url = match animal {
+            #[cfg(feature = "dog")]
+            Animal::Dog => "https://dog-api.kinduff.com/api/facts?number={shard_size}",
+            #[cfg(feature = "cat")]
+            Animal::Cat => {
+                "https://cat-fact.herokuapp.com/facts/random?type=cat&amount={shard_size}"
+            }
+        };
+        Self {
+            url: url.to_string(),
+            mirrors: vec![],
+            format: animal,
+        }
+    }
+
+    pub fn is_builtin(&self) -> bool {
+        *self == Self::builtin(self.format)
+    }
+}
+
+// An entry of the providers file; the format defaults to the animal's own one.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProviderEntry {
+    url: String,
+    #[serde(default)]
+    mirrors: Vec<String>,
+    format: Option<String>,
+}
+
+// The file maps animals to their providers, e.g.
+// `{"cat": [{"url": "https://a.com/cats?n={shard_size}"}, {"url": "https://b.com/facts", "format": "dog"}]}`.
+// Animals missing from the file keep their built-in provider.
+pub fn load_providers(path: &Path) -> Result<HashMap<Animal, Vec<Provider>>, AppError> {
+    let json = std::fs::read_to_string(path).map_err(|e| {
+        AppError::InvalidConfig(format!("Can't read providers file {:?}: {e}", path))
+    })?;
+    parse_providers(&json)
+}
+
+fn parse_providers(json: &str) -> Result<HashMap<Animal, Vec<Provider>>, AppError> {
+    let entries: HashMap<String, Vec<ProviderEntry>> = serde_json::from_str(json)
+        .map_err(|e| AppError::InvalidConfig(format!("Invalid providers file: {e}")))?;
+    let mut providers = HashMap::with_capacity(entries.len());
+    for (animal, entries) in entries {
+        let animal: Animal = animal.parse().map_err(AppError::InvalidConfig)?;
+        if entries.is_empty() {
+            return Err(AppError::InvalidConfig(format!(
+                "No providers listed for animal `{animal}`"
+            )));
+        }
+        let list = entries
+            .into_iter()
+            .map(|entry| {
+                let format = match entry.format {
+                    Some(format) => format.parse().map_err(AppError::InvalidConfig)?,
+                    None => animal,
+                };
+                Ok(Provider {
+                    url: entry.url,
+                    mirrors: entry.mirrors,
+                    format,
+                })
+            })
+            .collect::<Result<_, AppError>>()?;
+        providers.insert(animal, list);
+    }
+    Ok(providers)
+}
+
+// Every `PROBE_INTERVAL`-th request goes to a mirror other than the fastest one,
+// so that the latencies of the others stay up to date.
+const PROBE_INTERVAL: usize = 10;
+
+// Latencies of the mirrors of a provider. Failures are counted separately, as a mirror
+// responding quickly with invalid data isn't slow.
+#[derive(Debug)]
+pub struct MirrorLatencies(Mutex<MirrorState>);
+
+#[derive(Debug)]
+struct MirrorState {
+    // Of the successful requests only
+    latencies: Vec<DurationStats>,
+    // Failed requests since the last successful one
+    failures: Vec<u32>,
+    requests: usize,
+}
+
+impl MirrorLatencies {
+    pub fn new(mirror_num: usize) -> Self {
+        Self(Mutex::new(MirrorState {
+            latencies: vec![DurationStats::default(); mirror_num],
+            failures: vec![0; mirror_num],
+            requests: 0,
+        }))
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MirrorState> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn snapshot(&self) -> Vec<DurationStats> {
+        self.state().latencies.clone()
+    }
+
+    pub fn record(&self, mirror: usize, latency: Duration) {
+        let mut state = self.state();
+        state.latencies[mirror].record(latency);
+        state.failures[mirror] = 0;
+    }
+
+    pub fn record_failure(&self, mirror: usize) {
+        self.state().failures[mirror] += 1;
+    }
+
+    // The order in which the mirrors are to be tried: the failing mirrors go last
+    // (the more failures, the later), the unmeasured ones go first, then the fastest one
+    // (or a probed one), then the others from the fastest.
+    pub fn order(&self) -> Vec<usize> {
+        let mut state = self.state();
+        let (latencies, failures) = (&state.latencies, &state.failures);
+        let mut order: Vec<_> = (0..latencies.len()).collect();
+        order.sort_by_key(|i| (failures[*i], latencies[*i].count > 0, latencies[*i].average));
+        // Failing mirrors are probed too, so that they're used again once they recover.
+        let measured = (0..latencies.len()).all(|i| latencies[i].count > 0 || failures[i] > 0);
+        state.requests += 1;
+        if order.len() > 1 && measured && state.requests % PROBE_INTERVAL == 0 {
+            // The slower mirrors are probed in turn
+            let probed = 1 + (state.requests / PROBE_INTERVAL) % (order.len() - 1);
+            order.swap(0, probed);
+        }
+        order
+    }
+}
+
+#[cfg(all(test, feature = "dog", feature = "cat"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_providers() {
+        let providers = parse_providers(
+            r#"{"cat": [
+                {"url": "https://cat-fact.herokuapp.com/facts/random?type=cat&amount={shard_size}"},
+                {"url": "http://mirror/dogs?n={shard_size}", "format": "dog"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(providers.len(), 1);
+        let cat = &providers[&Animal::Cat];
+        assert!(cat[0].is_builtin());
+        assert_eq!(cat[0].format, Animal::Cat);
+        assert!(!cat[1].is_builtin());
+        assert_eq!(cat[1].format, Animal::Dog);
+        assert_eq!(cat[1].url(7), "http://mirror/dogs?n=7");
+        assert_eq!(cat[1].mirror_num(), 1);
+
+        for invalid in [
+            r#"{"cow": [{"url": "http://a"}]}"#,
+            r#"{"cat": [{"url": "http://a", "format": "cow"}]}"#,
+            r#"{"cat": [{"uri": "http://a"}]}"#,
+            r#"{"cat": []}"#,
+            r#"{"cat": [{"url": "http://a", "mirrors": "http://b"}]}"#,
+            r#"[]"#,
+        ] {
+            assert!(
+                matches!(parse_providers(invalid), Err(AppError::InvalidConfig(_))),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_mirror_order() {
+        let latencies = MirrorLatencies::new(3);
+        latencies.record(1, Duration::from_millis(10));
+        // The unmeasured mirrors are tried first
+        assert_eq!(latencies.order()[..2], [0, 2]);
+        latencies.record(0, Duration::from_millis(30));
+        latencies.record(2, Duration::from_millis(20));
+
+        let mut first = vec![];
+        for _ in 0..2 * PROBE_INTERVAL {
+            let order = latencies.order();
+            first.push(order[0]);
+            assert_eq!(order.len(), 3);
+        }
+        assert_eq!(
+            first.iter().filter(|m| **m == 1).count(),
+            2 * PROBE_INTERVAL - 2
+        );
+        // Both slower mirrors are probed
+        assert!(first.contains(&0));
+        assert!(first.contains(&2));
+
+        // A failing mirror goes last regardless of its latency, until it succeeds again
+        latencies.record_failure(1);
+        assert_eq!(latencies.snapshot()[1].average, Duration::from_millis(10));
+        assert_eq!(latencies.order(), [2, 0, 1]);
+        latencies.record(1, Duration::from_millis(10));
+        assert_eq!(latencies.order()[0], 1);
+    }
+}
@@ -0,0 +1,72 @@
+// Ids of the facts returned recently to any client, so that the same fact isn't
+// returned twice in quick succession. Unlike the exclusions supplied by clients,
+// this memory is global and bounded.
+
+use std::collections::VecDeque;
+use std::sync::{Mutex, PoisonError};
+
+use crate::FactId;
+
+pub struct RecentFacts {
+    // The oldest id is at the front
+    ids: Mutex<VecDeque<FactId>>,
+    capacity: usize,
+}
+
+impl RecentFacts {
+    // A zero capacity disables the memory. The buffer grows as ids are pushed,
+    // so that a huge capacity doesn't allocate memory upfront.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            ids: Mutex::new(VecDeque::new()),
+            capacity,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    // The buffer is small, so a linear search is cheap enough.
+    pub fn contains(&self, id: FactId) -> bool {
+        self.ids().contains(&id)
+    }
+
+    pub fn push(&self, id: FactId) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut ids = self.ids();
+        if ids.len() == self.capacity {
+            ids.pop_front();
+        }
+        ids.push_back(id);
+    }
+
+    // Losing a few ids is harmless, so a poisoned buffer is used as is.
+    fn ids(&self) -> std::sync::MutexGuard<'_, VecDeque<FactId>> {
+        self.ids.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_recent_facts() {
+        let recent = RecentFacts::new(2);
+        for fact in ["a", "b", "c"] {
+            recent.push(FactId::of(fact));
+        }
+        // The oldest id has been evicted
+        assert!(!recent.contains(FactId::of("a")));
+        assert!(recent.contains(FactId::of("b")));
+        assert!(recent.contains(FactId::of("c")));
+        assert_eq!(recent.ids().len(), 2);
+
+        let disabled = RecentFacts::new(0);
+        disabled.push(FactId::of("a"));
+        assert!(!disabled.contains(FactId::of("a")));
+    }
+}
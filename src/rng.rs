@@ -0,0 +1,58 @@
+// The source of randomness of fact selection. It's a part of `AppState`, so that
+// tests (or apps embedding the server) can make the selection deterministic.
+
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+pub trait RngSource: Send + Sync {
+    // A fact is selected with a single RNG, which mustn't be held across an `await`.
+    fn rng(&self) -> Box<dyn RngCore + '_>;
+}
+
+// The default source; each thread has its own RNG, so requests don't contend for it.
+pub struct ThreadRngSource;
+
+impl RngSource for ThreadRngSource {
+    fn rng(&self) -> Box<dyn RngCore + '_> {
+        Box::new(rand::thread_rng())
+    }
+}
+
+// A single seeded RNG shared by all requests, so the sequence of selected facts only
+// depends on the seed and the order of requests.
+pub struct SeededRngSource(Mutex<StdRng>);
+
+impl SeededRngSource {
+    pub fn new(seed: u64) -> Self {
+        Self(Mutex::new(StdRng::seed_from_u64(seed)))
+    }
+}
+
+impl RngSource for SeededRngSource {
+    fn rng(&self) -> Box<dyn RngCore + '_> {
+        Box::new(LockedRng(
+            self.0.lock().unwrap_or_else(PoisonError::into_inner),
+        ))
+    }
+}
+
+struct LockedRng<'a>(MutexGuard<'a, StdRng>);
+
+impl RngCore for LockedRng<'_> {
+    fn next_u32(&mut self) -> u32 {
+        self.0.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.0.try_fill_bytes(dest)
+    }
+}
@@ -0,0 +1,249 @@
+// Decides which shard a fact is read from and in which order the shards are refreshed,
+// so that the policies (see `ShardSelection`, `freshness_weighted` and `RefreshOrder`)
+// are kept in one place. The state of round-robins is kept per animal.
+
+use chrono::{DateTime, Utc};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use crate::animals::Animal;
+use crate::config::{RefreshOrder, ServerConfig, ShardSelection};
+use crate::errors::AppError;
+use crate::Shard;
+
+// Stale shards get a small weight rather than zero, so that they can still be read
+// if there's nothing fresher.
+pub const STALE_SHARD_WEIGHT: f64 = 0.05;
+
+// The weight of a shard decreases linearly with its age.
+pub fn freshness_weight(timestamp: DateTime<Utc>, staleness_sec: i64) -> f64 {
+    let age = (Utc::now() - timestamp).num_seconds().max(0) as f64;
+    (1.0 - age / staleness_sec.max(1) as f64).max(STALE_SHARD_WEIGHT)
+}
+
+#[derive(Default)]
+struct Cursor {
+    // Index of the next shard to read for `ShardSelection::RoundRobin`
+    next_shard: AtomicUsize,
+    // Current weights of the smooth weighted round-robin (as in nginx),
+    // see `ShardSelection::WeightedRoundRobin`
+    current_weights: Mutex<Vec<f64>>,
+}
+
+pub struct ShardScheduler {
+    selection: ShardSelection,
+    freshness_weighted: bool,
+    refresh_order: RefreshOrder,
+    staleness_sec: i64,
+    cursors: HashMap<Animal, Cursor>,
+}
+
+impl ShardScheduler {
+    pub fn new(cfg: &ServerConfig) -> Self {
+        Self {
+            selection: cfg.shard_selection,
+            freshness_weighted: cfg.freshness_weighted,
+            refresh_order: cfg.refresh_order,
+            staleness_sec: cfg.shard_staleness_sec,
+            cursors: cfg
+                .animals
+                .iter()
+                .map(|spec| (spec.animal, Cursor::default()))
+                .collect(),
+        }
+    }
+
+    // The shard of the animal to read a fact from; `None` if there are no shards.
+    pub fn select<'a, R: Rng>(
+        &self,
+        animal: Animal,
+        shards: &'a [Mutex<Shard>],
+        rng: &mut R,
+    ) -> Result<Option<&'a Mutex<Shard>>, AppError> {
+        if shards.is_empty() {
+            return Ok(None);
+        }
+        let cursor = self.cursors.get(&animal);
+        let shard = match (self.selection, cursor) {
+            _ if self.freshness_weighted => WeightedIndex::new(self.weights(shards)?)
+                .map(|d| &shards[d.sample(rng)])
+                .ok(),
+            (ShardSelection::Random, _) | (_, None) => shards.choose(rng),
+            (ShardSelection::RoundRobin, Some(cursor)) => {
+                let i = cursor.next_shard.fetch_add(1, Ordering::Relaxed);
+                shards.get(i % shards.len())
+            }
+            (ShardSelection::WeightedRoundRobin, Some(cursor)) => {
+                let weights = self.weights(shards)?;
+                let total: f64 = weights.iter().sum();
+                let mut current = cursor
+                    .current_weights
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                current.resize(shards.len(), 0.0);
+                let mut best = 0;
+                for (i, weight) in weights.iter().enumerate() {
+                    current[i] += weight;
+                    if current[i] > current[best] {
+                        best = i;
+                    }
+                }
+                current[best] -= total;
+                shards.get(best)
+            }
+        };
+        Ok(shard)
+    }
+
+    fn weights(&self, shards: &[Mutex<Shard>]) -> Result<Vec<f64>, AppError> {
+        let mut weights = Vec::with_capacity(shards.len());
+        for shard in shards {
+            weights.push(freshness_weight(
+                shard.lock()?.timestamp,
+                self.staleness_sec,
+            ));
+        }
+        Ok(weights)
+    }
+
+    // The order of refreshing the given shard sets, as `(set, shard)` pairs. The animals are
+    // interleaved, so that a slow provider doesn't hold the refresh of the others back.
+    pub fn refresh_order(&self, sets: &[(usize, &[Mutex<Shard>])]) -> Vec<(usize, usize)> {
+        let shard_num = sets.iter().map(|(_, shards)| shards.len()).max();
+        let mut order: Vec<_> = (0..shard_num.unwrap_or(0))
+            .flat_map(|j| {
+                sets.iter()
+                    .filter(move |(_, shards)| j < shards.len())
+                    .map(move |(i, shards)| (*i, j, shards))
+            })
+            .collect();
+        if self.refresh_order == RefreshOrder::StalestFirst {
+            // A poisoned shard can't be refreshed anyway, its position doesn't matter.
+            // The sort is stable, so the animals remain interleaved among equal timestamps.
+            order.sort_by_cached_key(|(_, j, shards)| {
+                shards[*j]
+                    .lock()
+                    .map_or(DateTime::<Utc>::MIN_UTC, |shard| shard.timestamp)
+            });
+        }
+        order.into_iter().map(|(i, j, _)| (i, j)).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::AnimalSpec;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn animal() -> Animal {
+        *<Animal as clap::ValueEnum>::value_variants()
+            .first()
+            .unwrap()
+    }
+
+    fn scheduler(selection: ShardSelection) -> ShardScheduler {
+        let cfg = ServerConfig::default()
+            .with_shard_selection(selection)
+            .with_shard_staleness_sec(10)
+            .with_animals(vec![AnimalSpec::from(animal())]);
+        ShardScheduler::new(&cfg)
+    }
+
+    // Shards of the given ages (sec)
+    fn shards(ages: &[i64]) -> Vec<Mutex<Shard>> {
+        ages.iter()
+            .map(|age| {
+                let mut shard = Shard::new(vec![]);
+                shard.timestamp = Utc::now() - chrono::Duration::seconds(*age);
+                Mutex::new(shard)
+            })
+            .collect()
+    }
+
+    // Indices of the shards selected `n` times in a row
+    fn selections(scheduler: &ShardScheduler, shards: &[Mutex<Shard>], n: usize) -> Vec<usize> {
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..n)
+            .map(|_| {
+                let shard = scheduler
+                    .select(animal(), shards, &mut rng)
+                    .unwrap()
+                    .unwrap();
+                shards.iter().position(|s| std::ptr::eq(s, shard)).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_freshness_weight() {
+        let now = Utc::now();
+        let sec = chrono::Duration::seconds;
+        assert_eq!(freshness_weight(now, 10), 1.0);
+        assert_eq!(freshness_weight(now - sec(5), 10), 0.5);
+        assert_eq!(freshness_weight(now - sec(10), 10), STALE_SHARD_WEIGHT);
+        assert_eq!(freshness_weight(now - sec(100), 10), STALE_SHARD_WEIGHT);
+    }
+
+    #[test]
+    fn test_no_shards() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for selection in <ShardSelection as clap::ValueEnum>::value_variants() {
+            let scheduler = scheduler(*selection);
+            assert!(scheduler.select(animal(), &[], &mut rng).unwrap().is_none());
+        }
+        assert!(scheduler(ShardSelection::Random)
+            .refresh_order(&[])
+            .is_empty());
+    }
+
+    #[test]
+    fn test_single_shard() {
+        let shards = shards(&[100]);
+        for selection in <ShardSelection as clap::ValueEnum>::value_variants() {
+            assert_eq!(selections(&scheduler(*selection), &shards, 3), [0, 0, 0]);
+        }
+        let order = scheduler(ShardSelection::Random).refresh_order(&[(0, &shards)]);
+        assert_eq!(order, [(0, 0)]);
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let shards = shards(&[0, 5, 20]);
+        let round_robin = scheduler(ShardSelection::RoundRobin);
+        assert_eq!(selections(&round_robin, &shards, 4), [0, 1, 2, 0]);
+
+        // The weights are 1, 0.5 and 0.05, so the stale shard gets 1 turn of 31
+        let weighted = scheduler(ShardSelection::WeightedRoundRobin);
+        let mut counts = [0; 3];
+        for i in selections(&weighted, &shards, 31) {
+            counts[i] += 1;
+        }
+        assert_eq!(counts, [20, 10, 1]);
+        // The turns are spread rather than bunched
+        assert_eq!(selections(&weighted, &shards, 3), [0, 1, 0]);
+    }
+
+    #[test]
+    fn test_refresh_order() {
+        let cats = shards(&[1, 30]);
+        let dogs = shards(&[10, 20, 5]);
+        let sets: [(usize, &[Mutex<Shard>]); 2] = [(0, &cats), (1, &dogs)];
+
+        let sequential = scheduler(ShardSelection::Random);
+        assert_eq!(
+            sequential.refresh_order(&sets),
+            [(0, 0), (1, 0), (0, 1), (1, 1), (1, 2)]
+        );
+        let cfg = ServerConfig::default().with_refresh_order(RefreshOrder::StalestFirst);
+        assert_eq!(
+            ShardScheduler::new(&cfg).refresh_order(&sets),
+            [(0, 1), (1, 1), (1, 0), (1, 2), (0, 0)]
+        );
+    }
+}
@@ -0,0 +1,61 @@
+// This module contains the Server-Sent Events endpoint emitting random facts periodically.
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures::stream::{self, Stream};
+use std::collections::HashSet;
+use std::convert::Infallible;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::time::{interval, Duration, Interval, MissedTickBehavior};
+
+use crate::errors::AppError;
+use crate::{select_fact, AppState, FactResponse};
+
+struct StreamState {
+    state: AppState,
+    ticker: Interval,
+    // Released when the stream is dropped, i.e. once the client disconnects
+    _permit: OwnedSemaphorePermit,
+}
+
+// No background task is spawned: the stream is polled by the response body only,
+// so it's dropped together with the connection.
+pub(crate) async fn fact_stream(
+    State(state): State<AppState>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    let permit = state.streams.clone().try_acquire_owned().map_err(|_| {
+        tracing::debug!("Too many fact streams");
+        StatusCode::SERVICE_UNAVAILABLE
+    })?;
+    let mut ticker = interval(Duration::from_secs(state.cfg.stream_interval_sec));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let stream_state = StreamState {
+        state,
+        ticker,
+        _permit: permit,
+    };
+    let events = stream::unfold(stream_state, |mut s| async move {
+        // The first tick is immediate
+        s.ticker.tick().await;
+        let event = match select_fact(&s.state, &HashSet::new()) {
+            Ok((animal, fact)) => Event::default()
+                .event("fact")
+                .json_data(FactResponse {
+                    animal: animal.to_string(),
+                    fact,
+                    id: None,
+                    lang: None,
+                })
+                .ok()?,
+            // The facts may appear after a refresh, the client doesn't need to reconnect.
+            Err(AppError::NoData) => Event::default().comment("no facts available"),
+            Err(e) => {
+                tracing::error!("Fact stream terminated: {:?}", e);
+                return None;
+            }
+        };
+        Some((Ok(event), s))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
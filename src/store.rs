@@ -0,0 +1,174 @@
+// Optional storage of the shards shared by replicas: one of them (the writer) fetches facts
+// from the providers and saves the shards, while the others load the shards from the store
+// instead of requesting the providers. The shards of `AppState` serve as a local cache
+// of the store, which is reloaded on each refresh (i.e. every `shard_refresh_sec`).
+
+use chrono::{TimeZone, Utc};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+
+use crate::animals::Animal;
+use crate::errors::AppError;
+use crate::Shard;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredShard {
+    pub facts: Vec<String>,
+    // Absent in the shards saved by older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weights: Option<Vec<u32>>,
+    // Unix time of the fetch in milliseconds, so that the age of the facts is preserved
+    pub timestamp_ms: i64,
+}
+
+impl From<&Shard> for StoredShard {
+    fn from(shard: &Shard) -> Self {
+        Self {
+            facts: shard.facts.clone(),
+            weights: shard.weights.clone(),
+            timestamp_ms: shard.timestamp.timestamp_millis(),
+        }
+    }
+}
+
+impl From<StoredShard> for Shard {
+    fn from(stored: StoredShard) -> Self {
+        let mut shard = Shard::new(stored.facts);
+        shard.weights = stored.weights;
+        if let Some(timestamp) = Utc.timestamp_millis_opt(stored.timestamp_ms).single() {
+            shard.timestamp = timestamp;
+        }
+        shard
+    }
+}
+
+pub trait ShardStore: Send + Sync {
+    // `None` if the shard hasn't been saved yet
+    fn load(
+        &self,
+        animal: Animal,
+        shard: usize,
+    ) -> BoxFuture<'_, Result<Option<StoredShard>, AppError>>;
+
+    fn save(
+        &self,
+        animal: Animal,
+        shard: usize,
+        stored: StoredShard,
+    ) -> BoxFuture<'_, Result<(), AppError>>;
+}
+
+// Shares the shards between the states of a single process, e.g. in tests.
+#[derive(Default)]
+pub struct MemoryStore(Mutex<HashMap<(Animal, usize), StoredShard>>);
+
+impl ShardStore for MemoryStore {
+    fn load(
+        &self,
+        animal: Animal,
+        shard: usize,
+    ) -> BoxFuture<'_, Result<Option<StoredShard>, AppError>> {
+        let stored = self
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&(animal, shard))
+            .cloned();
+        Box::pin(async move { Ok(stored) })
+    }
+
+    fn save(
+        &self,
+        animal: Animal,
+        shard: usize,
+        stored: StoredShard,
+    ) -> BoxFuture<'_, Result<(), AppError>> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert((animal, shard), stored);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[cfg(feature = "redis")]
+pub use self::redis_store::RedisStore;
+
+#[cfg(feature = "redis")]
+mod redis_store {
+    use futures::future::BoxFuture;
+    use redis::aio::ConnectionManager;
+    use redis::AsyncCommands;
+    use tokio::sync::OnceCell;
+
+    use super::{ShardStore, StoredShard};
+    use crate::animals::Animal;
+    use crate::errors::AppError;
+
+    const KEY_PREFIX: &str = concat!(env!("CARGO_PKG_NAME"), ":shard");
+
+    // Each shard is stored as JSON under its own key, e.g. `shuttle-test:shard:cat:0`.
+    pub struct RedisStore {
+        client: redis::Client,
+        // Connected on first use, as the state is initialized synchronously;
+        // the manager reconnects by itself if the connection is lost.
+        connection: OnceCell<ConnectionManager>,
+    }
+
+    impl RedisStore {
+        pub fn new(url: &str) -> Result<Self, AppError> {
+            // The URL may contain a password, so it isn't logged.
+            let client = redis::Client::open(url)
+                .map_err(|e| AppError::InvalidConfig(format!("Invalid Redis URL: {e}")))?;
+            Ok(Self {
+                client,
+                connection: OnceCell::new(),
+            })
+        }
+
+        async fn connection(&self) -> Result<ConnectionManager, AppError> {
+            let connection = self
+                .connection
+                .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+                .await?;
+            Ok(connection.clone())
+        }
+    }
+
+    fn key(animal: Animal, shard: usize) -> String {
+        format!("{}:{}:{}", KEY_PREFIX, animal, shard)
+    }
+
+    impl ShardStore for RedisStore {
+        fn load(
+            &self,
+            animal: Animal,
+            shard: usize,
+        ) -> BoxFuture<'_, Result<Option<StoredShard>, AppError>> {
+            Box::pin(async move {
+                let json: Option<String> = self.connection().await?.get(key(animal, shard)).await?;
+                json.map(|json| serde_json::from_str(&json).map_err(AppError::JsonParsingError))
+                    .transpose()
+            })
+        }
+
+        fn save(
+            &self,
+            animal: Animal,
+            shard: usize,
+            stored: StoredShard,
+        ) -> BoxFuture<'_, Result<(), AppError>> {
+            Box::pin(async move {
+                let json = serde_json::to_string(&stored).map_err(AppError::JsonParsingError)?;
+                let () = self
+                    .connection()
+                    .await?
+                    .set(key(animal, shard), json)
+                    .await?;
+                Ok(())
+            })
+        }
+    }
+}
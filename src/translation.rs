@@ -0,0 +1,58 @@
+// Facts are provided in English only, so they can be translated on request
+// with a third-party translation provider.
+
+use serde::Deserialize;
+
+use crate::animals::fetch_url;
+use crate::errors::AppError;
+use crate::AppState;
+
+const DEFAULT_LANG: &str = "en";
+
+#[derive(Deserialize)]
+struct Translation {
+    text: String,
+}
+
+// Returns the language of the returned fact along with the fact itself. Translation
+// is an optional feature, so the original fact is returned if it's unavailable.
+pub(crate) async fn translate_or_keep(
+    state: &AppState,
+    lang: &str,
+    fact: String,
+) -> (String, String) {
+    let lang = lang.to_lowercase();
+    let supported = state
+        .cfg
+        .translation_langs
+        .iter()
+        .any(|l| l.eq_ignore_ascii_case(&lang));
+    if lang == DEFAULT_LANG || !supported {
+        return (DEFAULT_LANG.to_string(), fact);
+    }
+    let Some(url) = &state.cfg.translation_url else {
+        return (DEFAULT_LANG.to_string(), fact);
+    };
+    match translate(state, url, &lang, &fact).await {
+        Ok(translation) => (lang, translation),
+        Err(e) => {
+            tracing::warn!("Translation into {:?} failed: {:?}", lang, e);
+            (DEFAULT_LANG.to_string(), fact)
+        }
+    }
+}
+
+async fn translate(
+    state: &AppState,
+    url: &str,
+    lang: &str,
+    text: &str,
+) -> Result<String, AppError> {
+    let url = reqwest::Url::parse_with_params(url, &[("lang", lang), ("text", text)])
+        .map_err(|e| AppError::InvalidConfig(format!("Invalid translation URL: {e}")))?;
+    let body = fetch_url(&state.client, url.as_str(), state.cfg.max_response_bytes).await?;
+    match serde_json::from_str::<Translation>(&body) {
+        Ok(translation) => Ok(translation.text),
+        Err(e) => Err(AppError::JsonParsingError(e)),
+    }
+}
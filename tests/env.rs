@@ -0,0 +1,47 @@
+// Sets the config with environment variables. Modifying the environment races with
+// anything reading it in other threads (e.g. reqwest looking for proxies), so this is
+// the only test of its binary.
+#![cfg(all(feature = "dog", feature = "cat"))]
+
+use clap::Parser;
+
+use shuttle_test::animals::Animal;
+use shuttle_test::config::{AnimalSpec, ServerConfig, ShardSelection};
+
+fn spec(animal: Animal, weight: u32) -> AnimalSpec {
+    AnimalSpec { animal, weight }
+}
+
+#[test]
+fn test_env() {
+    for (name, value) in [
+        ("SHUTTLE_TEST_SHARD_SIZE", "20"),
+        ("SHUTTLE_TEST_ANIMALS", "cat:3,dog"),
+        ("SHUTTLE_TEST_SHARD_SELECTION", "round-robin"),
+        ("SHUTTLE_TEST_FRESHNESS_WEIGHTED", "true"),
+        // Unprefixed variables are meant for something else
+        ("SHARD_NUM", "7"),
+        ("REDIS_URL", "redis://localhost:6379"),
+    ] {
+        std::env::set_var(name, value);
+    }
+
+    let from_env = ServerConfig::try_parse_from(["shuttle-test"]).unwrap();
+    assert_eq!(from_env.shard_size, 20);
+    assert_eq!(
+        from_env.animals,
+        [spec(Animal::Cat, 3), spec(Animal::Dog, 1)]
+    );
+    assert_eq!(from_env.shard_selection, ShardSelection::RoundRobin);
+    assert!(from_env.freshness_weighted);
+    assert_eq!(from_env.shard_num, ServerConfig::default().shard_num);
+    assert_eq!(from_env.redis_url, None);
+
+    // Arguments take precedence
+    let overridden =
+        ServerConfig::try_parse_from(["shuttle-test", "--shard-size", "30", "--animals", "dog"])
+            .unwrap();
+    assert_eq!(overridden.shard_size, 30);
+    assert_eq!(overridden.animals, [spec(Animal::Dog, 1)]);
+    assert_eq!(overridden.shard_selection, ShardSelection::RoundRobin);
+}
@@ -0,0 +1,72 @@
+// Uses the server as a library: its routes are mounted into another app,
+// and the facts are fetched from a local provider.
+#![cfg(feature = "cat")]
+
+use axum::extract::Query;
+use axum::routing::get;
+use axum::{Json, Router};
+use std::collections::HashMap;
+use std::net::TcpListener;
+
+use shuttle_test::animals::Animal;
+use shuttle_test::config::ServerConfig;
+use shuttle_test::{build_router, init_state, refresh_shards};
+
+// Serves `app` on a random local port
+fn serve(app: Router) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service()),
+    );
+    format!("http://{}", addr)
+}
+
+async fn cat_facts(Query(params): Query<HashMap<String, usize>>) -> Json<serde_json::Value> {
+    let facts: Vec<_> = (0..params["amount"])
+        .map(|i| serde_json::json!({ "text": format!("local cat fact #{}", i) }))
+        .collect();
+    Json(facts.into())
+}
+
+#[tokio::test]
+async fn test_build_router() {
+    let provider = serve(Router::new().route("/facts", get(cat_facts)));
+    let providers_file =
+        std::env::temp_dir().join(format!("shuttle-test-library-{}.json", std::process::id()));
+    let providers = serde_json::json!({
+        "cat": [{ "url": format!("{}/facts?amount={{shard_size}}", provider) }],
+    });
+    std::fs::write(&providers_file, providers.to_string()).unwrap();
+
+    let cfg = ServerConfig::default()
+        .with_animals(vec!["cat".parse::<Animal>().unwrap().into()])
+        .with_shard_size(5)
+        .with_providers_file(Some(providers_file.clone()));
+    let state = init_state(cfg).unwrap();
+    refresh_shards(&state, 1).await.into_result().unwrap();
+    std::fs::remove_file(providers_file).unwrap();
+
+    let app = Router::new()
+        .route("/", get(|| async { "The app's own route" }))
+        .nest("/animals", build_router(state));
+    let url = serve(app);
+
+    let body = reqwest::get(format!("{}/animals/fact", url))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let fact: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(fact["animal"], "cat");
+    assert!(fact["fact"].as_str().unwrap().starts_with("local cat fact"));
+    let health = reqwest::get(format!("{}/animals/health", url))
+        .await
+        .unwrap();
+    assert_eq!(health.status(), 200);
+    let own = reqwest::get(&url).await.unwrap().text().await.unwrap();
+    assert_eq!(own, "The app's own route");
+}